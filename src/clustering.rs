@@ -0,0 +1,546 @@
+use rand::distributions::WeightedIndex;
+use rand::Rng;
+use rand_distr::Normal;
+
+use crate::simulation::{purchase_probability, Customer, ProblemSettings};
+
+/// How the platform assigns predicted groups to customers.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Segmentation {
+    ConfusionMatrix(ConfusionMatrix), // predicted group drawn from P(predicted | true)
+    Clustering(ClusteringMethod),     // clustering of customer features observed before the run
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClusteringMethod {
+    KMeans,
+    GaussianMixture, // diagonal covariances
+}
+
+/// Re-estimation of the predicted groups during a run from what the platform
+/// has observed about every customer so far.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Resegmentation {
+    pub interval: f64, // periods between two re-estimations
+    pub method: ClusteringMethod,
+}
+
+/// P(predicted group | true group). Row `g` is the distribution of the
+/// predicted group of a customer from true group `g`, so the number of columns
+/// is the number of predicted groups and may differ from the number of groups.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfusionMatrix(pub Vec<Vec<f64>>);
+
+impl ConfusionMatrix {
+    /// Every true group is recognised.
+    pub fn identity(n_groups: usize) -> Self {
+        Self::merged(&(0..n_groups).collect::<Vec<_>>(), n_groups)
+    }
+
+    /// The true group is kept with probability `accuracy`, otherwise a uniformly
+    /// random predicted group is drawn. True groups without a predicted group of
+    /// the same index are always assigned at random.
+    pub fn from_accuracy(n_groups: usize, n_predicted: usize, accuracy: f64) -> Self {
+        let noise = (1.0 - accuracy) / n_predicted as f64;
+        let rows = (0..n_groups)
+            .map(|g| {
+                (0..n_predicted)
+                    .map(|p| {
+                        if g >= n_predicted {
+                            1.0 / n_predicted as f64
+                        } else if p == g {
+                            accuracy + noise
+                        } else {
+                            noise
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        Self(rows)
+    }
+
+    /// Several true groups share a segment: true group `g` is always predicted
+    /// as `segment_of[g]`.
+    pub fn merged(segment_of: &[usize], n_predicted: usize) -> Self {
+        let rows = segment_of
+            .iter()
+            .map(|&segment| (0..n_predicted).map(|p| if p == segment { 1.0 } else { 0.0 }).collect())
+            .collect();
+        Self(rows)
+    }
+
+    /// True group `g` is split evenly over `n_segments[g]` consecutive segments.
+    pub fn split(n_segments: &[usize]) -> Self {
+        let n_predicted: usize = n_segments.iter().sum();
+        let mut first = 0;
+        let rows = n_segments
+            .iter()
+            .map(|&n| {
+                let row = (0..n_predicted)
+                    .map(|p| if p >= first && p < first + n { 1.0 / n as f64 } else { 0.0 })
+                    .collect();
+                first += n;
+                row
+            })
+            .collect();
+        Self(rows)
+    }
+
+    /// Mixes every row with a uniform distribution, `accuracy` = 1 keeps the matrix.
+    pub fn with_noise(&self, accuracy: f64) -> Self {
+        let rows = self
+            .0
+            .iter()
+            .map(|row| {
+                let uniform = 1.0 / row.len() as f64;
+                row.iter().map(|p| accuracy * p + (1.0 - accuracy) * uniform).collect()
+            })
+            .collect();
+        Self(rows)
+    }
+
+    pub fn num_predicted_groups(&self) -> usize {
+        self.0.first().map_or(0, |row| row.len())
+    }
+
+    pub fn validate(&self, n_groups: usize, n_predicted: usize) -> Result<(), String> {
+        if self.0.len() != n_groups {
+            return Err(format!("confusion matrix has {} rows, expected {}", self.0.len(), n_groups));
+        }
+        for (g, row) in self.0.iter().enumerate() {
+            if row.len() != n_predicted {
+                return Err(format!("row {} has {} columns, expected {}", g, row.len(), n_predicted));
+            }
+            if row.iter().any(|p| *p < 0.0) {
+                return Err(format!("row {} has negative probabilities", g));
+            }
+            let total: f64 = row.iter().sum();
+            if (total - 1.0).abs() > 1e-6 {
+                return Err(format!("row {} sums to {}, expected 1", g, total));
+            }
+        }
+        Ok(())
+    }
+
+    /// One sampler per true group.
+    pub fn samplers(&self) -> Vec<WeightedIndex<f64>> {
+        self.0.iter().map(|row| WeightedIndex::new(row).unwrap()).collect()
+    }
+}
+
+/// Observable features of a customer gathered during an observation window
+/// before the simulated horizon: number of visits, mean inter-arrival time,
+/// share of offers accepted, mean price paid (0 without purchases) and a noisy
+/// wtp signal. Offers in the
+/// window are uniform random prices and do not change the customer's state.
+pub fn observe_features(customer: &Customer, settings: &ProblemSettings, rng: &mut impl Rng) -> Vec<f64> {
+    let noise = Normal::new(0.0, settings.wtp_signal_noise).unwrap();
+    let mut t = 0.0;
+    let mut last_price = 0.0;
+    let mut visits = 0;
+    let mut purchases = Vec::new();
+
+    loop {
+        let next_t = customer.next_visit(rng, t, last_price);
+        if next_t as f64 > settings.observation_window {
+            break;
+        }
+        t = next_t;
+        visits += 1;
+        last_price = rng.gen_range(0.0..settings.max_price);
+        if rng.gen::<f64>() < purchase_probability(last_price, customer.wtp(), settings.sigmoid_scale) {
+            purchases.push(last_price);
+        }
+    }
+
+    let mean_interarrival = if visits > 0 { t as f64 / visits as f64 } else { settings.observation_window };
+    let mean_purchase_price = if purchases.is_empty() {
+        0.0
+    } else {
+        purchases.iter().sum::<f64>() / purchases.len() as f64
+    };
+    let acceptance = if visits > 0 { purchases.len() as f64 / visits as f64 } else { 0.0 };
+    let wtp_signal = customer.wtp() * (1.0 + rng.sample(noise));
+
+    vec![visits as f64, mean_interarrival, acceptance, mean_purchase_price, wtp_signal]
+}
+
+/// Position of the wtp signal in the vector returned by `observe_features`.
+pub const WTP_SIGNAL: usize = 4;
+
+/// Scales every feature to zero mean and unit variance.
+pub fn standardize(data: &mut [Vec<f64>]) {
+    if data.is_empty() {
+        return;
+    }
+    let n = data.len() as f64;
+    for feature in 0..data[0].len() {
+        let mean = data.iter().map(|row| row[feature]).sum::<f64>() / n;
+        let var = data.iter().map(|row| (row[feature] - mean).powi(2)).sum::<f64>() / n;
+        let std = if var > 0.0 { var.sqrt() } else { 1.0 };
+        for row in data.iter_mut() {
+            row[feature] = (row[feature] - mean) / std;
+        }
+    }
+}
+
+fn squared_distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| (x - y).powi(2)).sum()
+}
+
+fn nearest(point: &[f64], centroids: &[Vec<f64>]) -> usize {
+    (0..centroids.len())
+        .min_by(|&a, &b| {
+            squared_distance(point, &centroids[a])
+                .partial_cmp(&squared_distance(point, &centroids[b]))
+                .unwrap()
+        })
+        .unwrap()
+}
+
+/// Lloyd's algorithm with k-means++ initialisation. Returns the cluster of every row.
+pub fn kmeans(data: &[Vec<f64>], k: usize, max_iter: usize, rng: &mut impl Rng) -> Vec<usize> {
+    if data.is_empty() {
+        return vec![];
+    }
+
+    let mut centroids = vec![data[rng.gen_range(0..data.len())].clone()];
+    while centroids.len() < k {
+        let distances: Vec<f64> = data
+            .iter()
+            .map(|row| squared_distance(row, &centroids[nearest(row, &centroids)]))
+            .collect();
+        let total: f64 = distances.iter().sum();
+        if total <= 0.0 {
+            centroids.push(data[rng.gen_range(0..data.len())].clone());
+            continue;
+        }
+        let mut target = rng.gen::<f64>() * total;
+        let mut chosen = data.len() - 1;
+        for (idx, distance) in distances.iter().enumerate() {
+            target -= distance;
+            if target <= 0.0 {
+                chosen = idx;
+                break;
+            }
+        }
+        centroids.push(data[chosen].clone());
+    }
+
+    let mut labels: Vec<usize> = data.iter().map(|row| nearest(row, &centroids)).collect();
+    for _ in 0..max_iter {
+        let n_features = data[0].len();
+        let mut sums = vec![vec![0.0; n_features]; k];
+        let mut counts = vec![0; k];
+        for (row, &label) in data.iter().zip(labels.iter()) {
+            counts[label] += 1;
+            for (sum, value) in sums[label].iter_mut().zip(row) {
+                *sum += value;
+            }
+        }
+        for cluster in 0..k {
+            // keep the old centroid of an empty cluster
+            if counts[cluster] > 0 {
+                centroids[cluster] = sums[cluster].iter().map(|sum| sum / counts[cluster] as f64).collect();
+            }
+        }
+
+        let new_labels: Vec<usize> = data.iter().map(|row| nearest(row, &centroids)).collect();
+        if new_labels == labels {
+            break;
+        }
+        labels = new_labels;
+    }
+    labels
+}
+
+/// EM for a mixture of `k` gaussians with diagonal covariances, initialised
+/// from k-means. Returns the most likely component of every row.
+pub fn gaussian_mixture(data: &[Vec<f64>], k: usize, max_iter: usize, rng: &mut impl Rng) -> Vec<usize> {
+    let min_variance = 1e-6;
+    let mut labels = kmeans(data, k, max_iter, rng);
+    if data.is_empty() {
+        return labels;
+    }
+    let n = data.len();
+    let n_features = data[0].len();
+
+    // responsibilities start as the hard k-means assignment
+    let mut resp = vec![vec![0.0; k]; n];
+    for (row, &label) in resp.iter_mut().zip(labels.iter()) {
+        row[label] = 1.0;
+    }
+
+    let mut log_likelihood = f64::NEG_INFINITY;
+    for _ in 0..max_iter {
+        // M step
+        let mut weights = vec![0.0; k];
+        let mut means = vec![vec![0.0; n_features]; k];
+        let mut variances = vec![vec![0.0; n_features]; k];
+        for cluster in 0..k {
+            let total: f64 = resp.iter().map(|r| r[cluster]).sum::<f64>().max(1e-12);
+            weights[cluster] = total / n as f64;
+            for feature in 0..n_features {
+                let mean = data.iter().zip(&resp).map(|(row, r)| r[cluster] * row[feature]).sum::<f64>() / total;
+                let var = data
+                    .iter()
+                    .zip(&resp)
+                    .map(|(row, r)| r[cluster] * (row[feature] - mean).powi(2))
+                    .sum::<f64>()
+                    / total;
+                means[cluster][feature] = mean;
+                variances[cluster][feature] = var.max(min_variance);
+            }
+        }
+
+        // E step
+        let mut new_log_likelihood = 0.0;
+        for (row, r) in data.iter().zip(resp.iter_mut()) {
+            let log_probs: Vec<f64> = (0..k)
+                .map(|cluster| {
+                    let mut log_prob = weights[cluster].max(1e-300).ln();
+                    for feature in 0..n_features {
+                        let var = variances[cluster][feature];
+                        log_prob -= 0.5
+                            * ((2.0 * std::f64::consts::PI * var).ln()
+                                + (row[feature] - means[cluster][feature]).powi(2) / var);
+                    }
+                    log_prob
+                })
+                .collect();
+            let max_log_prob = log_probs.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let log_norm = max_log_prob + log_probs.iter().map(|lp| (lp - max_log_prob).exp()).sum::<f64>().ln();
+            for (value, log_prob) in r.iter_mut().zip(&log_probs) {
+                *value = (log_prob - log_norm).exp();
+            }
+            new_log_likelihood += log_norm;
+        }
+
+        let converged = (new_log_likelihood - log_likelihood).abs() < 1e-6 * new_log_likelihood.abs().max(1.0);
+        log_likelihood = new_log_likelihood;
+        if converged {
+            break;
+        }
+    }
+
+    for (label, r) in labels.iter_mut().zip(&resp) {
+        *label = (0..k).max_by(|&a, &b| r[a].partial_cmp(&r[b]).unwrap()).unwrap();
+    }
+    labels
+}
+
+/// Cluster ids are arbitrary. Maps them onto group ids by ranking the clusters
+/// by their mean `score` and the groups by `group_scores`, so the cluster with
+/// the i-th lowest score becomes the group with the i-th lowest group score.
+pub fn align_labels(labels: &[usize], score: &[f64], group_scores: &[f64]) -> Vec<usize> {
+    let k = group_scores.len();
+    let mut sums = vec![0.0; k];
+    let mut counts = vec![0; k];
+    for (&label, value) in labels.iter().zip(score) {
+        sums[label] += value;
+        counts[label] += 1;
+    }
+    let cluster_means: Vec<f64> = (0..k)
+        .map(|c| if counts[c] > 0 { sums[c] / counts[c] as f64 } else { 0.0 })
+        .collect();
+
+    let mut clusters: Vec<usize> = (0..k).collect();
+    clusters.sort_by(|&a, &b| cluster_means[a].partial_cmp(&cluster_means[b]).unwrap());
+    let mut groups: Vec<usize> = (0..k).collect();
+    groups.sort_by(|&a, &b| group_scores[a].partial_cmp(&group_scores[b]).unwrap());
+
+    let mut mapping = vec![0; k];
+    for (cluster, group) in clusters.into_iter().zip(groups) {
+        mapping[cluster] = group;
+    }
+    labels.iter().map(|&label| mapping[label]).collect()
+}
+
+pub fn cluster(data: &[Vec<f64>], k: usize, method: ClusteringMethod, rng: &mut impl Rng) -> Vec<usize> {
+    let max_iter = 100;
+    match method {
+        ClusteringMethod::KMeans => kmeans(data, k, max_iter, rng),
+        ClusteringMethod::GaussianMixture => gaussian_mixture(data, k, max_iter, rng),
+    }
+}
+
+/// Relabels clusters so they overlap as much as possible with the previous
+/// groups, greedily matching the largest overlaps first. Keeps segment ids
+/// stable when customers are re-segmented during a run.
+pub fn match_labels(labels: &[usize], previous: &[usize], k: usize) -> Vec<usize> {
+    let mut overlap = vec![vec![0usize; k]; k];
+    for (&label, &group) in labels.iter().zip(previous) {
+        overlap[label][group] += 1;
+    }
+
+    let mut mapping = vec![None; k];
+    let mut taken = vec![false; k];
+    for _ in 0..k {
+        let (cluster, group) = (0..k)
+            .filter(|&c| mapping[c].is_none())
+            .flat_map(|c| (0..k).filter(|&g| !taken[g]).map(move |g| (c, g)))
+            .max_by_key(|&(c, g)| overlap[c][g])
+            .unwrap();
+        mapping[cluster] = Some(group);
+        taken[group] = true;
+    }
+    labels.iter().map(|&label| mapping[label].unwrap()).collect()
+}
+
+/// New predicted groups for the customers that have visited at least once,
+/// from their in-run features (see `CustomerObservations::features`). Returns
+/// `(customer index, predicted group)` pairs, empty if fewer customers than
+/// segments have been observed.
+pub fn resegment_customers(
+    customers: &[Customer],
+    settings: &ProblemSettings,
+    method: ClusteringMethod,
+    rng: &mut impl Rng,
+) -> Vec<(usize, usize)> {
+    let k = settings.num_predicted_groups as usize;
+    let observed: Vec<usize> = (0..customers.len())
+        .filter(|&idx| customers[idx].observations.visits > 0)
+        .collect();
+    if observed.len() < k {
+        return vec![];
+    }
+
+    let mut data: Vec<Vec<f64>> = observed.iter().map(|&idx| customers[idx].observations.features()).collect();
+    standardize(&mut data);
+    let labels = cluster(&data, k, method, rng);
+    let previous: Vec<usize> = observed.iter().map(|&idx| customers[idx].predicted_group() as usize).collect();
+    let labels = match_labels(&labels, &previous, k);
+
+    observed.into_iter().zip(labels).collect()
+}
+
+/// Predicted group of every customer from clustering its observed features.
+pub fn segment_customers(customers: &[Customer], settings: &ProblemSettings, rng: &mut impl Rng) -> Vec<usize> {
+    let k = settings.num_predicted_groups as usize;
+
+    let raw: Vec<Vec<f64>> = customers
+        .iter()
+        .map(|customer| observe_features(customer, settings, rng))
+        .collect();
+    let wtp_signal: Vec<f64> = raw.iter().map(|features| features[WTP_SIGNAL]).collect();
+    let mut data = raw;
+    standardize(&mut data);
+
+    let method = match settings.segmentation {
+        Segmentation::Clustering(method) => method,
+        Segmentation::ConfusionMatrix(_) => ClusteringMethod::KMeans,
+    };
+    let labels = cluster(&data, k, method, rng);
+
+    // without a one-to-one match between clusters and groups fall back to index order
+    let group_scores: Vec<f64> = if k == settings.group_means.len() {
+        settings.group_means.clone()
+    } else {
+        (0..k).map(|g| g as f64).collect()
+    };
+    align_labels(&labels, &wtp_signal, &group_scores)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    /// Three tight, well separated blobs of four points each.
+    fn blobs() -> Vec<Vec<f64>> {
+        [[0.0, 0.0], [10.0, 0.0], [0.0, 10.0]]
+            .iter()
+            .flat_map(|center| {
+                [[0.1, 0.0], [-0.1, 0.0], [0.0, 0.1], [0.0, -0.1]]
+                    .iter()
+                    .map(move |offset| vec![center[0] + offset[0], center[1] + offset[1]])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn kmeans_recovers_separated_blobs() {
+        let data = blobs();
+        for seed in 0..10 {
+            let labels = kmeans(&data, 3, 100, &mut StdRng::seed_from_u64(seed));
+            for blob in labels.chunks(4) {
+                assert!(blob.iter().all(|&label| label == blob[0]), "seed {}: {:?}", seed, labels);
+            }
+            assert_ne!(labels[0], labels[4]);
+            assert_ne!(labels[0], labels[8]);
+            assert_ne!(labels[4], labels[8]);
+        }
+    }
+
+    #[test]
+    fn kmeans_of_no_rows_is_empty() {
+        assert!(kmeans(&[], 3, 100, &mut StdRng::seed_from_u64(0)).is_empty());
+    }
+
+    #[test]
+    fn align_labels_ranks_clusters_by_their_mean_score() {
+        // cluster 0 has the highest scores, cluster 2 the lowest
+        let labels = [0, 0, 1, 1, 2, 2];
+        let score = [9.0, 11.0, 4.0, 6.0, 1.0, 1.0];
+        let group_scores = [5.0, 1.0, 10.0];
+        assert_eq!(align_labels(&labels, &score, &group_scores), vec![2, 2, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn match_labels_undoes_a_permutation() {
+        let previous = [0, 0, 1, 1, 1, 2, 2];
+        let permuted: Vec<usize> = previous.iter().map(|&group| [2, 0, 1][group]).collect();
+        assert_eq!(match_labels(&permuted, &previous, 3), previous.to_vec());
+    }
+
+    #[test]
+    fn match_labels_follows_the_largest_overlap() {
+        // cluster 1 mostly holds former group 0, one customer moved
+        let previous = [0, 0, 0, 1, 1];
+        let labels = [1, 1, 0, 0, 0];
+        assert_eq!(match_labels(&labels, &previous, 2), vec![0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn constructed_matrices_are_valid() {
+        assert!(ConfusionMatrix::identity(3).validate(3, 3).is_ok());
+        assert!(ConfusionMatrix::from_accuracy(3, 2, 0.7).validate(3, 2).is_ok());
+        assert!(ConfusionMatrix::merged(&[0, 0, 1], 2).validate(3, 2).is_ok());
+        assert!(ConfusionMatrix::split(&[1, 3]).validate(2, 4).is_ok());
+        assert!(ConfusionMatrix::split(&[1, 3]).with_noise(0.5).validate(2, 4).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_malformed_matrices() {
+        let matrix = ConfusionMatrix(vec![vec![0.5, 0.5], vec![1.0, 0.0]]);
+        assert!(matrix.validate(3, 2).is_err());
+        assert!(matrix.validate(2, 3).is_err());
+        assert!(ConfusionMatrix(vec![vec![1.5, -0.5]]).validate(1, 2).is_err());
+        assert!(ConfusionMatrix(vec![vec![0.5, 0.4]]).validate(1, 2).is_err());
+    }
+
+    #[test]
+    fn merged_and_split_rows() {
+        assert_eq!(
+            ConfusionMatrix::merged(&[1, 0, 1], 2).0,
+            vec![vec![0.0, 1.0], vec![1.0, 0.0], vec![0.0, 1.0]]
+        );
+        assert_eq!(
+            ConfusionMatrix::split(&[1, 2]).0,
+            vec![vec![1.0, 0.0, 0.0], vec![0.0, 0.5, 0.5]]
+        );
+        assert_eq!(ConfusionMatrix::split(&[1, 2]).num_predicted_groups(), 3);
+    }
+
+    #[test]
+    fn from_accuracy_keeps_the_true_group() {
+        let matrix = ConfusionMatrix::from_accuracy(3, 2, 0.6);
+        assert!((matrix.0[0][0] - 0.8).abs() < 1e-12);
+        assert!((matrix.0[0][1] - 0.2).abs() < 1e-12);
+        // no predicted group with the index of the third group
+        assert_eq!(matrix.0[2], vec![0.5, 0.5]);
+        assert_eq!(ConfusionMatrix::from_accuracy(2, 2, 1.0), ConfusionMatrix::identity(2));
+    }
+}
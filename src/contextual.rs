@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+use rand_distr::StandardNormal;
+
+use crate::mab::{Algorithm, Outcome, PricingContext};
+use crate::simulation::{ProblemSettings, SEASON_LENGTH};
+
+/// How the contextual bandit trades off exploration and exploitation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContextualStrategy {
+    LinUCB,
+    LinearThompson,
+}
+
+impl std::fmt::Display for ContextualStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContextualStrategy::LinUCB => write!(f, "LinUCB"),
+            ContextualStrategy::LinearThompson => write!(f, "LinearThompson"),
+        }
+    }
+}
+
+pub struct ContextualSettings {
+    pub min_price: f64,
+    pub max_price: f64,
+    pub n_arms: usize,
+    pub strategy: ContextualStrategy,
+    pub alpha: f64,           // width of the LinUCB confidence bound
+    pub regularization: f64,  // ridge penalty, the prior precision of the weights
+    pub posterior_scale: f64, // scale of the linear Thompson sampling posterior
+}
+
+/// Ridge regression of the (normalised) revenue of one price arm on the context.
+/// Keeps the inverse design matrix up to date with Sherman-Morrison updates.
+#[derive(Clone, Debug)]
+struct LinearArm {
+    price: f64,
+    a_inv: Vec<Vec<f64>>,
+    b: Vec<f64>,
+    num_pulls: usize,
+}
+
+impl LinearArm {
+    fn new(price: f64, dim: usize, regularization: f64) -> Self {
+        let mut a_inv = vec![vec![0.0; dim]; dim];
+        for (i, row) in a_inv.iter_mut().enumerate() {
+            row[i] = 1.0 / regularization;
+        }
+        Self {
+            price,
+            a_inv,
+            b: vec![0.0; dim],
+            num_pulls: 0,
+        }
+    }
+
+    fn theta(&self) -> Vec<f64> {
+        mat_vec(&self.a_inv, &self.b)
+    }
+
+    fn ucb(&self, x: &[f64], alpha: f64) -> f64 {
+        let a_inv_x = mat_vec(&self.a_inv, x);
+        dot(&self.theta(), x) + alpha * dot(x, &a_inv_x).max(0.0).sqrt()
+    }
+
+    fn thompson(&self, x: &[f64], scale: f64, rng: &mut impl Rng) -> f64 {
+        let chol = cholesky(&self.a_inv);
+        let z: Vec<f64> = (0..x.len()).map(|_| rng.sample(StandardNormal)).collect();
+        let noise = mat_vec(&chol, &z);
+        let theta: Vec<f64> = self.theta().iter().zip(noise).map(|(t, n)| t + scale * n).collect();
+        dot(&theta, x)
+    }
+
+    fn update(&mut self, x: &[f64], reward: f64) {
+        let a_inv_x = mat_vec(&self.a_inv, x);
+        let denom = 1.0 + dot(x, &a_inv_x);
+        for (i, row) in self.a_inv.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value -= a_inv_x[i] * a_inv_x[j] / denom;
+            }
+        }
+        for (b, xi) in self.b.iter_mut().zip(x) {
+            *b += reward * xi;
+        }
+        self.num_pulls += 1;
+    }
+}
+
+/// Contextual bandit over a grid of prices. The context is the one-hot
+/// predicted group, the visit index, the time, the seasonality phase and the
+/// last price offered to the customer, so what is learned in one cell carries
+/// over to similar contexts.
+pub struct ContextualBandit {
+    arms: Vec<LinearArm>,
+    strategy: ContextualStrategy,
+    alpha: f64,
+    posterior_scale: f64,
+    n_groups: usize,
+    n_visits: f64,
+    n_periods: f64,
+    max_price: f64,
+    last_choice: Option<(Vec<f64>, usize)>, // context and arm of the last offer
+    pending: HashMap<usize, (Vec<f64>, usize)>, // offer_id -> context and arm, until the outcome is known
+    frozen: bool,
+}
+
+impl ContextualBandit {
+    pub fn new(settings: &ProblemSettings, algorithm_settings: &ContextualSettings) -> Self {
+        let n_groups = settings.num_predicted_groups as usize;
+        let dim = n_groups + 6;
+        let arms = settings
+            .price_grid
+            .prices(algorithm_settings.min_price, algorithm_settings.max_price, algorithm_settings.n_arms)
+            .into_iter()
+            .map(|price| LinearArm::new(price, dim, algorithm_settings.regularization))
+            .collect();
+        Self {
+            arms,
+            strategy: algorithm_settings.strategy,
+            alpha: algorithm_settings.alpha,
+            posterior_scale: algorithm_settings.posterior_scale,
+            n_groups,
+            n_visits: settings.n_visits as f64,
+            n_periods: settings.n_periods as f64,
+            max_price: settings.max_price,
+            last_choice: None,
+            pending: HashMap::new(),
+            frozen: false,
+        }
+    }
+
+    pub fn features(&self, context: &PricingContext) -> Vec<f64> {
+        let mut x = vec![0.0; self.n_groups];
+        x[context.group_id] = 1.0;
+        let phase = 2.0 * std::f64::consts::PI * context.t / SEASON_LENGTH;
+        x.extend([
+            1.0,
+            context.visit as f64 / self.n_visits,
+            context.t / self.n_periods,
+            phase.sin(),
+            phase.cos(),
+            context.history.last_offer.unwrap_or(0.0) / self.max_price,
+        ]);
+        x
+    }
+
+    pub fn num_pulls(&self) -> Vec<(f64, usize)> {
+        self.arms.iter().map(|arm| (arm.price, arm.num_pulls)).collect()
+    }
+}
+
+impl Algorithm for ContextualBandit {
+    fn get_price(&mut self, context: &PricingContext) -> f64 {
+        let x = self.features(context);
+        let mut rng = rand::thread_rng();
+        let scores: Vec<f64> = self
+            .arms
+            .iter()
+            .map(|arm| match self.strategy {
+                _ if self.frozen => dot(&arm.theta(), &x),
+                ContextualStrategy::LinUCB => arm.ucb(&x, self.alpha),
+                ContextualStrategy::LinearThompson => arm.thompson(&x, self.posterior_scale, &mut rng),
+            })
+            .collect();
+        let best = (0..scores.len())
+            .max_by(|&a, &b| scores[a].partial_cmp(&scores[b]).unwrap())
+            .unwrap();
+        if !self.frozen {
+            self.last_choice = Some((x, best));
+        }
+        self.arms[best].price
+    }
+
+    fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+        self.last_choice = None;
+    }
+
+    fn register_offer(&mut self, offer_id: usize) {
+        if let Some(choice) = self.last_choice.take() {
+            self.pending.insert(offer_id, choice);
+        }
+    }
+
+    fn attribute_outcome(&mut self, offer_id: usize, _context: &PricingContext, outcome: Option<Outcome>) {
+        if let (Some((x, arm)), Some(outcome)) = (self.pending.remove(&offer_id), outcome) {
+            self.arms[arm].update(&x, outcome.reward() / self.max_price);
+        }
+    }
+
+    fn update(&mut self, _context: &PricingContext, outcome: Outcome) {
+        if let Some((x, arm)) = self.last_choice.take() {
+            self.arms[arm].update(&x, outcome.reward() / self.max_price);
+        }
+    }
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn mat_vec(m: &[Vec<f64>], v: &[f64]) -> Vec<f64> {
+    m.iter().map(|row| dot(row, v)).collect()
+}
+
+/// Lower triangular `L` with `L * L^T = m` for a symmetric positive definite `m`.
+fn cholesky(m: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let n = m.len();
+    let mut l = vec![vec![0.0; n]; n];
+    for i in 0..n {
+        for j in 0..=i {
+            let sum: f64 = (0..j).map(|k| l[i][k] * l[j][k]).sum();
+            if i == j {
+                l[i][j] = (m[i][i] - sum).max(1e-12).sqrt();
+            } else {
+                l[i][j] = (m[i][j] - sum) / l[j][j];
+            }
+        }
+    }
+    l
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::tests::problem_settings;
+    use crate::simulation::CustomerObservations;
+
+    fn contextual_settings(strategy: ContextualStrategy) -> ContextualSettings {
+        ContextualSettings {
+            min_price: 100.0,
+            max_price: 600.0,
+            n_arms: 6,
+            strategy,
+            alpha: 1.0,
+            regularization: 1.0,
+            posterior_scale: 0.1,
+        }
+    }
+
+    fn context(customer: usize, group_id: usize, visit: usize, t: f64) -> PricingContext {
+        PricingContext {
+            customer,
+            group_id,
+            visit,
+            t,
+            period: t as usize,
+            history: CustomerObservations::default(),
+        }
+    }
+
+    #[test]
+    fn features_encode_the_group_and_scale_the_rest() {
+        let bandit = ContextualBandit::new(&problem_settings(), &contextual_settings(ContextualStrategy::LinUCB));
+        let mut context = context(0, 1, 2, 5.0);
+        context.history.last_offer = Some(350.0);
+        let x = bandit.features(&context);
+        assert_eq!(x.len(), 2 + 6);
+        assert_eq!(&x[..3], &[0.0, 1.0, 1.0]);
+        assert!((x[3] - 2.0 / 3.0).abs() < 1e-12);
+        assert_eq!(x[4], 0.5);
+        assert!((x[5].powi(2) + x[6].powi(2) - 1.0).abs() < 1e-12);
+        assert_eq!(x[7], 0.5);
+    }
+
+    #[test]
+    fn sherman_morrison_keeps_the_inverse_design_matrix() {
+        let xs = [vec![1.0, 0.5, 0.0], vec![0.0, 1.0, 2.0], vec![1.0, 1.0, 1.0], vec![0.3, 0.0, 1.0]];
+        let mut arm = LinearArm::new(100.0, 3, 2.0);
+        for x in &xs {
+            arm.update(x, 1.0);
+        }
+        // A = 2 I + sum of x x^T
+        let a: Vec<Vec<f64>> = (0..3)
+            .map(|i| (0..3).map(|j| if i == j { 2.0 } else { 0.0 } + xs.iter().map(|x| x[i] * x[j]).sum::<f64>()).collect())
+            .collect();
+        for (i, row) in arm.a_inv.iter().enumerate() {
+            for j in 0..3 {
+                let product: f64 = row.iter().zip(&a).map(|(value, a_row)| value * a_row[j]).sum();
+                assert!((product - if i == j { 1.0 } else { 0.0 }).abs() < 1e-9);
+            }
+        }
+        assert_eq!(arm.num_pulls, 4);
+    }
+
+    #[test]
+    fn theta_recovers_a_linear_reward() {
+        let mut arm = LinearArm::new(100.0, 2, 1e-6);
+        for i in 0..50 {
+            let x = [1.0, i as f64 / 50.0];
+            arm.update(&x, 0.2 + 0.6 * x[1]);
+        }
+        let theta = arm.theta();
+        assert!((theta[0] - 0.2).abs() < 1e-4 && (theta[1] - 0.6).abs() < 1e-4, "{:?}", theta);
+    }
+
+    #[test]
+    fn cholesky_factor_reproduces_the_matrix() {
+        let m = vec![vec![4.0, 2.0, 0.4], vec![2.0, 3.0, 0.5], vec![0.4, 0.5, 1.0]];
+        let l = cholesky(&m);
+        for i in 0..3 {
+            for j in 0..3 {
+                let product: f64 = (0..3).map(|k| l[i][k] * l[j][k]).sum();
+                assert!((product - m[i][j]).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn late_outcome_updates_the_arm_chosen_for_the_offer() {
+        let settings = problem_settings();
+        let mut bandit = ContextualBandit::new(&settings, &contextual_settings(ContextualStrategy::LinUCB));
+        let first = context(0, 0, 0, 1.0);
+        let price = bandit.get_price(&first);
+        bandit.register_offer(0);
+        // another offer is made before the first is resolved
+        bandit.get_price(&context(1, 1, 0, 1.5));
+        bandit.register_offer(1);
+
+        bandit.attribute_outcome(0, &first, Some(Outcome::Sold { price }));
+        let pulled: Vec<f64> = bandit.num_pulls().into_iter().filter(|(_, n)| *n > 0).map(|(p, _)| p).collect();
+        assert_eq!(pulled, vec![price]);
+        let arm = bandit.arms.iter().find(|arm| arm.price == price).unwrap();
+        let x = bandit.features(&first);
+        assert!(arm.b.iter().zip(&x).all(|(b, xi)| (b - price / settings.max_price * xi).abs() < 1e-12));
+    }
+
+    #[test]
+    fn frozen_bandit_does_not_learn() {
+        let mut bandit = ContextualBandit::new(&problem_settings(), &contextual_settings(ContextualStrategy::LinearThompson));
+        bandit.set_frozen(true);
+        let context = context(0, 0, 0, 1.0);
+        let price = bandit.get_price(&context);
+        bandit.register_offer(0);
+        bandit.attribute_outcome(0, &context, Some(Outcome::Sold { price }));
+        bandit.update(&context, Outcome::Sold { price });
+        assert!(bandit.num_pulls().iter().all(|(_, n)| *n == 0));
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+
+use crate::mab::{Algorithm, Outcome, PricingContext};
+use crate::simulation::ProblemSettings;
+
+/// How the continuous-action bandit spreads its prices over the interval.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContinuousStrategy {
+    Zooming, // adaptive discretisation: new prices are activated where confidence balls leave gaps
+    GPUCB,   // Gaussian process over the price interval, evaluated on a fine grid
+}
+
+impl std::fmt::Display for ContinuousStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContinuousStrategy::Zooming => write!(f, "Zooming"),
+            ContinuousStrategy::GPUCB => write!(f, "GPUCB"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ContinuousSettings {
+    pub min_price: f64,
+    pub max_price: f64,
+    pub strategy: ContinuousStrategy,
+    pub zoom_scale: f64,      // confidence radius multiplier, in units of the price interval
+    pub max_arms: usize,      // upper bound on the active prices per group and period
+    pub gp_grid_size: usize,  // candidate prices of GP-UCB
+    pub gp_length_scale: f64, // kernel length scale, as a share of the price interval
+    pub gp_noise: f64,        // standard deviation of the normalised revenue of one offer
+    pub gp_beta: f64,         // width of the GP-UCB confidence bound
+}
+
+#[derive(Clone, Debug)]
+struct ZoomingArm {
+    position: f64, // price mapped to [0, 1]
+    total_reward: f64,
+    num_pulls: usize,
+}
+
+impl ZoomingArm {
+    fn new(position: f64) -> Self {
+        Self {
+            position,
+            total_reward: 0.0,
+            num_pulls: 0,
+        }
+    }
+
+    fn mean(&self) -> f64 {
+        if self.num_pulls == 0 {
+            0.0
+        } else {
+            self.total_reward / self.num_pulls as f64
+        }
+    }
+}
+
+/// Posterior of the normalised revenue on a grid of prices under a squared
+/// exponential kernel. Observations are rank-one updates of mean and covariance.
+#[derive(Clone, Debug)]
+struct GaussianProcess {
+    mean: Vec<f64>,
+    cov: Vec<Vec<f64>>,
+}
+
+impl GaussianProcess {
+    fn new(grid_size: usize, length_scale: f64) -> Self {
+        let position = |i: usize| i as f64 / (grid_size - 1) as f64;
+        let cov = (0..grid_size)
+            .map(|i| {
+                (0..grid_size)
+                    .map(|j| (-(position(i) - position(j)).powi(2) / (2.0 * length_scale.powi(2))).exp())
+                    .collect()
+            })
+            .collect();
+        Self {
+            mean: vec![0.0; grid_size],
+            cov,
+        }
+    }
+
+    fn update(&mut self, i: usize, reward: f64, noise: f64) {
+        let k: Vec<f64> = self.cov.iter().map(|row| row[i]).collect();
+        let denom = k[i] + noise.powi(2);
+        let residual = reward - self.mean[i];
+        for (j, mean) in self.mean.iter_mut().enumerate() {
+            *mean += k[j] * residual / denom;
+        }
+        for (j, row) in self.cov.iter_mut().enumerate() {
+            for (l, value) in row.iter_mut().enumerate() {
+                *value -= k[j] * k[l] / denom;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Cell {
+    Zooming(Vec<ZoomingArm>),
+    GaussianProcess(GaussianProcess),
+}
+
+/// Pricing bandit over the whole interval `[min_price, max_price]` instead of a
+/// fixed grid, with one learner per (predicted group, period) like `MAB`.
+pub struct ContinuousBandit {
+    cells: Vec<Cell>, // group_id * n_periods + period
+    num_pulls: Vec<usize>,
+    settings: ContinuousSettings,
+    n_periods: usize,
+    last_choice: Option<(usize, usize)>, // cell and arm (or grid index) of the last offer
+    pending: HashMap<usize, (usize, usize)>, // offer_id -> cell and arm, until the outcome is known
+    frozen: bool,
+}
+
+impl ContinuousBandit {
+    pub fn new(settings: &ProblemSettings, algorithm_settings: &ContinuousSettings) -> Self {
+        let n_periods = settings.n_periods as usize;
+        let n_cells = settings.num_predicted_groups as usize * n_periods;
+        let cell = match algorithm_settings.strategy {
+            ContinuousStrategy::Zooming => Cell::Zooming(Vec::new()),
+            ContinuousStrategy::GPUCB => Cell::GaussianProcess(GaussianProcess::new(
+                algorithm_settings.gp_grid_size,
+                algorithm_settings.gp_length_scale,
+            )),
+        };
+        Self {
+            cells: vec![cell; n_cells],
+            num_pulls: vec![0; n_cells],
+            settings: algorithm_settings.clone(),
+            n_periods,
+            last_choice: None,
+            pending: HashMap::new(),
+            frozen: false,
+        }
+    }
+
+    fn to_price(&self, position: f64) -> f64 {
+        self.settings.min_price + position * (self.settings.max_price - self.settings.min_price)
+    }
+
+    fn radius(&self, arm: &ZoomingArm, t: usize) -> f64 {
+        self.settings.zoom_scale * ((t as f64 + 2.0).ln() / (arm.num_pulls as f64 + 1.0)).sqrt()
+    }
+
+    /// Active prices of a group and period with their number of offers, so the
+    /// resolution of the discretisation can be inspected.
+    pub fn active_prices(&self, group_id: usize, period: usize) -> Vec<(f64, usize)> {
+        match &self.cells[group_id * self.n_periods + period] {
+            Cell::Zooming(arms) => arms.iter().map(|arm| (self.to_price(arm.position), arm.num_pulls)).collect(),
+            Cell::GaussianProcess(gp) => {
+                let last = (gp.mean.len() - 1) as f64;
+                (0..gp.mean.len()).map(|i| (self.to_price(i as f64 / last), 0)).collect()
+            }
+        }
+    }
+
+    // Activates prices in the gaps left by the confidence balls of the active ones
+    fn cover(&mut self, cell: usize) {
+        let t = self.num_pulls[cell];
+        loop {
+            let Cell::Zooming(arms) = &self.cells[cell] else {
+                return;
+            };
+            if arms.len() >= self.settings.max_arms {
+                return;
+            }
+            let mut balls: Vec<(f64, f64)> = arms
+                .iter()
+                .map(|arm| {
+                    let r = self.radius(arm, t);
+                    (arm.position - r, arm.position + r)
+                })
+                .collect();
+            balls.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+            let mut covered_to = 0.0;
+            let mut gap = None;
+            for (start, end) in balls {
+                if start > covered_to {
+                    gap = Some((covered_to, start));
+                    break;
+                }
+                covered_to = f64::max(covered_to, end);
+            }
+            if gap.is_none() && covered_to < 1.0 {
+                gap = Some((covered_to, 1.0));
+            }
+            let Some((start, end)) = gap else {
+                return;
+            };
+            if let Cell::Zooming(arms) = &mut self.cells[cell] {
+                arms.push(ZoomingArm::new((start + end) / 2.0));
+            }
+        }
+    }
+
+    fn select(&self, cell: usize) -> (usize, f64) {
+        let t = self.num_pulls[cell];
+        match &self.cells[cell] {
+            Cell::Zooming(arms) => {
+                let best = (0..arms.len())
+                    .max_by(|&a, &b| {
+                        let score = |arm: &ZoomingArm| arm.mean() + 2.0 * self.radius(arm, t);
+                        score(&arms[a]).partial_cmp(&score(&arms[b])).unwrap()
+                    })
+                    .unwrap();
+                (best, arms[best].position)
+            }
+            Cell::GaussianProcess(gp) => {
+                let m = gp.mean.len() as f64;
+                let width = (self.settings.gp_beta * (m * (t as f64 + 1.0).powi(2)).ln()).sqrt();
+                let best = (0..gp.mean.len())
+                    .max_by(|&a, &b| {
+                        let score = |i: usize| gp.mean[i] + width * gp.cov[i][i].max(0.0).sqrt();
+                        score(a).partial_cmp(&score(b)).unwrap()
+                    })
+                    .unwrap();
+                (best, best as f64 / (m - 1.0))
+            }
+        }
+    }
+
+    // Price with the highest estimated revenue, without exploration bonus
+    fn select_greedy(&self, cell: usize) -> f64 {
+        match &self.cells[cell] {
+            Cell::Zooming(arms) => arms
+                .iter()
+                .filter(|arm| arm.num_pulls > 0)
+                .max_by(|a, b| a.mean().partial_cmp(&b.mean()).unwrap())
+                .map_or(0.5, |arm| arm.position),
+            Cell::GaussianProcess(gp) => {
+                let best = (0..gp.mean.len())
+                    .max_by(|&a, &b| gp.mean[a].partial_cmp(&gp.mean[b]).unwrap())
+                    .unwrap();
+                best as f64 / (gp.mean.len() - 1) as f64
+            }
+        }
+    }
+
+    fn learn(&mut self, cell: usize, arm: usize, reward: f64) {
+        let reward = reward / self.settings.max_price;
+        self.num_pulls[cell] += 1;
+        match &mut self.cells[cell] {
+            Cell::Zooming(arms) => {
+                arms[arm].total_reward += reward;
+                arms[arm].num_pulls += 1;
+            }
+            Cell::GaussianProcess(gp) => gp.update(arm, reward, self.settings.gp_noise),
+        }
+    }
+}
+
+impl Algorithm for ContinuousBandit {
+    fn get_price(&mut self, context: &PricingContext) -> f64 {
+        // an event exactly at the horizon belongs to the last period
+        let period = context.period.min(self.n_periods - 1);
+        let cell = context.group_id * self.n_periods + period;
+        if self.frozen {
+            return self.to_price(self.select_greedy(cell));
+        }
+        self.cover(cell);
+        let (arm, position) = self.select(cell);
+        self.last_choice = Some((cell, arm));
+        self.to_price(position)
+    }
+
+    fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+        self.last_choice = None;
+    }
+
+    fn register_offer(&mut self, offer_id: usize) {
+        if let Some(choice) = self.last_choice.take() {
+            self.pending.insert(offer_id, choice);
+        }
+    }
+
+    fn attribute_outcome(&mut self, offer_id: usize, _context: &PricingContext, outcome: Option<Outcome>) {
+        if let (Some((cell, arm)), Some(outcome)) = (self.pending.remove(&offer_id), outcome) {
+            self.learn(cell, arm, outcome.reward());
+        }
+    }
+
+    fn update(&mut self, _context: &PricingContext, outcome: Outcome) {
+        if let Some((cell, arm)) = self.last_choice.take() {
+            self.learn(cell, arm, outcome.reward());
+        }
+    }
+}
@@ -1,6 +1,5 @@
-use std::{collections::HashMap, fs, vec};
-use rand::Rng;
-use crate::{ evolution::PriceMatrix, logging::log_event_history, mab::Algorithm, simulation::{simulate_revenue, ProblemSettings} };
+use std::{collections::HashMap, fs, sync::Arc};
+use crate::{ event_sink::MemorySink, evolution::PriceMatrix, logging::log_event_history, mab::{Algorithm, Outcome, PricingContext}, simulation::{simulate_revenue_with_sink, ProblemSettings} };
 
 pub struct CustomSolution {
     pub price_matrix: PriceMatrix,
@@ -8,7 +7,6 @@ pub struct CustomSolution {
 
 impl CustomSolution {
     pub fn new(start_prices: Vec<f64>, settings: &ProblemSettings) -> Self {
-        let mut rng = rand::thread_rng();
         let mut prices = HashMap::new();
 
         for (g, start_price) in start_prices.iter().enumerate() {
@@ -24,35 +22,27 @@ impl CustomSolution {
             prices.insert(g as usize, group_map);
         }
 
-        let mut ind = Self {
+        Self {
             price_matrix: PriceMatrix(prices),
-        };
-        ind
+        }
     }
 }
 
-impl<'a> Algorithm for CustomSolution {
-    fn get_price(&mut self, group_id: usize, visit: usize, period: usize) -> i32 {
+impl Algorithm for CustomSolution {
+    fn get_price(&mut self, context: &PricingContext) -> f64 {
         // Since ES maintains a price matrix with visits and periods,
         // we'll use the first visit and period for now
         // TODO: Extend the Algorithm trait to handle multiple visits/periods
-        self.price_matrix.get_price(group_id, visit, period) as i32
+        self.price_matrix.get_price(context.group_id, context.visit, context.period)
     }
 
-    fn update_average_reward(
-        &mut self,
-        _group_id: usize,
-        _visit: usize,
-        _period: usize,
-        _reward: f64,
-        _price: i32
-    ) {
+    fn update(&mut self, _context: &PricingContext, _outcome: Outcome) {
         // ES doesn't update prices based on individual rewards
         // Instead, it uses the total fitness score for evolution
     }
 }
 
-pub fn simulate_custom(settings: &ProblemSettings, run_id: i32, writer: &mut csv::Writer<fs::File>) {
+pub fn simulate_custom(settings: &Arc<ProblemSettings>, run_id: i32, writer: &mut csv::Writer<fs::File>) {
 
     
 
@@ -72,14 +62,15 @@ pub fn simulate_custom(settings: &ProblemSettings, run_id: i32, writer: &mut csv
         let mut total_revenue = 0.0;
         let mut best_result = None;
         let mut best_revenue= 0.0;
-        for i in 0..n_runs {
+        for _ in 0..n_runs {
             let mut solution = CustomSolution::new(scenario.clone(), settings);
             
-            let res = simulate_revenue(&mut solution, settings);
+            let mut sink = MemorySink::new();
+            let res = simulate_revenue_with_sink(&mut solution, settings, &mut sink);
             
             
 
-            total_revenue += res.revenue as f64;
+            total_revenue += res.revenue;
             
             writer
                 .write_record(&[
@@ -95,15 +86,15 @@ pub fn simulate_custom(settings: &ProblemSettings, run_id: i32, writer: &mut csv
             // );
             
             // Track the best result (highest revenue)
-            if res.revenue as f64 > best_revenue {
-                best_revenue = res.revenue as f64;
-                best_result = Some(("A", res.clone()));
+            if res.revenue > best_revenue {
+                best_revenue = res.revenue;
+                best_result = Some(("A", res.clone(), sink.events));
             }
     }
         
-        if let Some((vector_type, best)) = best_result.as_ref() {
+        if let Some((vector_type, best, events)) = best_result.as_ref() {
             println!("\nBest result: Vector {} with revenue {:.2}", vector_type, best.revenue);
-            log_event_history((scenario_id as i32 + run_id), best, &settings);
+            log_event_history(scenario_id as i32 + run_id, events, settings);
         
             // let file = std::fs::OpenOptions::new()
             //     .write(true)
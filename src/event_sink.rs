@@ -0,0 +1,92 @@
+use std::fs::File;
+
+use crate::logging::{open_event_history, write_event};
+use crate::simulation::{ProblemSettings, SimulationEvent};
+
+/// Receives the visit/sold/quit events produced by `simulate_revenue_with_sink`.
+/// The caller decides whether events are dropped, kept in memory or streamed.
+pub trait EventSink {
+    fn record(&mut self, event: SimulationEvent);
+}
+
+/// Discards every event. Used by the optimizers, which only need the KPIs.
+pub struct NoopSink;
+
+impl EventSink for NoopSink {
+    fn record(&mut self, _event: SimulationEvent) {}
+}
+
+/// Keeps every event in memory.
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    pub events: Vec<SimulationEvent>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl EventSink for MemorySink {
+    fn record(&mut self, event: SimulationEvent) {
+        self.events.push(event);
+    }
+}
+
+/// Streams events straight into `./results/event_history.csv`.
+pub struct CsvSink {
+    writer: csv::Writer<File>,
+    run_id: i32,
+    loss_aversion: f64,
+}
+
+impl CsvSink {
+    pub fn new(run_id: i32, settings: &ProblemSettings) -> Self {
+        Self {
+            writer: open_event_history(),
+            run_id,
+            loss_aversion: settings.lambda,
+        }
+    }
+
+    /// Writes the buffered events to the file.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl EventSink for CsvSink {
+    fn record(&mut self, event: SimulationEvent) {
+        write_event(&mut self.writer, self.run_id, &event, self.loss_aversion);
+    }
+}
+
+// Best effort only, a panic while unwinding would abort. Call `flush` to see the error.
+impl Drop for CsvSink {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+/// Forwards the events of every `every`-th customer to the inner sink, so the
+/// sampled customers keep their complete trajectories.
+pub struct SamplingSink<S: EventSink> {
+    pub inner: S,
+    every: i32,
+}
+
+impl<S: EventSink> SamplingSink<S> {
+    pub fn new(inner: S, every: i32) -> Self {
+        assert!(every > 0, "sampling interval must be positive");
+        Self { inner, every }
+    }
+}
+
+impl<S: EventSink> EventSink for SamplingSink<S> {
+    fn record(&mut self, event: SimulationEvent) {
+        if event.customer % self.every == 0 {
+            self.inner.record(event);
+        }
+    }
+}
@@ -1,9 +1,10 @@
-use std::os::fd::IntoRawFd;
+use std::sync::Arc;
 use std::{collections::HashMap, fs::File};
 
 use crate::logging::log_population;
-use crate::mab::Algorithm;
-use crate::simulation::{simulate_revenue, ProblemSettings, SimulationEvent, SimulationResult};
+use crate::mab::{Algorithm, Outcome, PricingContext};
+use crate::price_grid::PriceGrid;
+use crate::simulation::{simulate_revenue, ProblemSettings, SimulationResult};
 use rand::Rng;
 use rand_distr::Normal;
 
@@ -69,24 +70,36 @@ impl PriceMatrix {
             }
         }
     }
+
+    /// Moves every price onto the closest displayed price in `[0, max_price]`,
+    /// so optimizers only tell apart prices that customers can tell apart.
+    pub fn snap(&mut self, grid: &PriceGrid, max_price: f64) {
+        for group_map in self.0.values_mut() {
+            for period_prices in group_map.values_mut() {
+                for price in period_prices.iter_mut() {
+                    *price = grid.snap_within(*price, 0.0, max_price);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
-pub struct Individual<'a> {
+pub struct Individual {
     pub prices: PriceMatrix,
-    pub simulation_result: SimulationResult<'a>,
+    pub simulation_result: SimulationResult,
     pub fitness_score: f64,
     pub ind_id: i32,
 }
 
-impl<'a> Individual<'a> {
+impl Individual {
     pub fn new(
         ind_id: i32,
         n_visits: usize,
         n_periods: usize,
         n_groups: usize,
-        settings: &'a ProblemSettings,
-        n_evals:i32
+        settings: &Arc<ProblemSettings>,
+        n_evals: i32,
     ) -> Self {
         let mut rng = rand::thread_rng();
         let mut prices = HashMap::new();
@@ -108,29 +121,21 @@ impl<'a> Individual<'a> {
             prices: PriceMatrix(prices),
             ind_id,
             fitness_score: 0.0,
-            simulation_result: SimulationResult {
-                regret: 0.0,
-                n_sold: 0.0,
-                avg_time_sold_at: 0.0,
-                event_history: vec![],
-                revenue: 0.0,
-                avg_regret: 0.0,
-                customers: vec![],
-                
-            },
+            simulation_result: SimulationResult::default(),
         };
+        ind.prices.snap(&settings.price_grid, settings.max_price);
 
         // println!("Initial prices: {:?}", ind.prices.0);
 
-        let result = simulate_and_average(&mut ind, settings, n_evals);
+        let result = simulate_and_average(&ind, settings, n_evals);
         ind.simulation_result = result.1;
         ind.fitness_score = result.0;
         ind
     }
 }
 
-impl<'a> Algorithm for Individual<'a> {
-    fn get_price(&mut self, group_id: usize, visit: usize, period: usize) -> i32 {
+impl Algorithm for Individual {
+    fn get_price(&mut self, context: &PricingContext) -> f64 {
         // Since ES maintains a price matrix with visits and periods,
         // we'll use the first visit and period for now
         // TODO: Extend the Algorithm trait to handle multiple visits/periods
@@ -139,30 +144,20 @@ impl<'a> Algorithm for Individual<'a> {
         //     "get_price: group_id: {}, visit: {}, period: {}",
         //     group_id, visit, period
         // );
-        let converted_period = period % 10;
+        let converted_period = context.period % 10;
 
-        self.prices.get_price(group_id, 0, converted_period) as i32 // converted_period) as i32
-        // self.prices.get_price(group_id, visit, period) as i32
+        self.prices.get_price(context.group_id, 0, converted_period) // converted_period)
+        // self.prices.get_price(context.group_id, context.visit, context.period)
     }
 
-    fn update_average_reward(
-        &mut self,
-        _group_id: usize,
-        _visit: usize,
-        _period: usize,
-        _reward: f64,
-        _price: i32,
-    ) {
+    fn update(&mut self, _context: &PricingContext, _outcome: Outcome) {
         // ES doesn't update prices based on individual rewards
         // Instead, it uses the total fitness score for evolution
     }
 }
 
 
-fn mutate_solution<'a>(
-    individual: &Individual<'a>,
-    settings: &ESSettings,
-) -> Individual<'a> {
+fn mutate_solution(individual: &Individual, settings: &ESSettings, problem_settings: &ProblemSettings) -> Individual {
     let mut new_prices = individual.prices.0.clone();
     let mut rng = rand::thread_rng();
 
@@ -179,27 +174,18 @@ fn mutate_solution<'a>(
             }
         }
     }
+    let mut prices = PriceMatrix(new_prices);
+    prices.snap(&problem_settings.price_grid, problem_settings.max_price);
     Individual {
-        prices: PriceMatrix(new_prices),
+        prices,
         fitness_score: 0.0,
         ind_id: individual.ind_id,
-        simulation_result: SimulationResult {
-            event_history: vec![],
-            avg_regret: 0.0,
-            regret: 0.0,
-            customers: vec![],
-            avg_time_sold_at: 0.0,
-            n_sold: 0.0,
-            revenue: 0.0,
-        },
+        simulation_result: SimulationResult::default(),
     }
 }
 
-fn mutate_solution_selective<'a>(
-    individual: &Individual<'a>,
-    settings: &ESSettings,
-    n_changes: usize,
-) -> Individual<'a> {
+#[allow(dead_code)]
+fn mutate_solution_selective(individual: &Individual, n_changes: usize) -> Individual {
     let mut new_prices = individual.prices.0.clone();
     let mut rng = rand::thread_rng();
     
@@ -208,7 +194,7 @@ fn mutate_solution_selective<'a>(
     for (g_idx, group_map) in new_prices.values_mut().enumerate() {
         for (w_idx, period_prices) in group_map.values_mut().enumerate() {
             for t_idx in 0..period_prices.len() {
-                if (w_idx == 0 && t_idx < 10) {
+                if w_idx == 0 && t_idx < 10 {
                     all_indices.push((g_idx, w_idx, t_idx));
                 }
             }
@@ -224,8 +210,6 @@ fn mutate_solution_selective<'a>(
             if let Some(group_map) = new_prices.get_mut(&g_idx) {
                 if let Some(period_prices) = group_map.get_mut(&w_idx) {
                     let price = &mut period_prices[t_idx];
-                    let normal = Normal::new(0.0, 1.0).unwrap();
-                    // let mutation = settings.mutation_strength * rng.sample(normal);
                     let mutation = rng.gen_range(0..700);   //settings.mutation_strength * rng.sample(normal);
         
                     *price = mutation as f64;
@@ -245,23 +229,14 @@ fn mutate_solution_selective<'a>(
         prices: PriceMatrix(new_prices),
         fitness_score: 0.0,
         ind_id: individual.ind_id,
-        simulation_result: SimulationResult {
-            event_history: vec![],
-            avg_regret: 0.0,
-            regret: 0.0,
-            customers: vec![],
-            avg_time_sold_at: 0.0,
-            n_sold: 0.0,
-            revenue: 0.0,
-        },
+        simulation_result: SimulationResult::default(),
     }
 }
 
 
 
-fn simulate_and_average<'a>(individual: &Individual<'a>, settings: &'a ProblemSettings, n_evals:i32) -> (f64, SimulationResult<'a>) {
+fn simulate_and_average(individual: &Individual, settings: &Arc<ProblemSettings>, _n_evals: i32) -> (f64, SimulationResult) {
     let mut total_revenue = 0.0;
-    let mut rng = rand::thread_rng();
     let n_runs = 10;
     let mut best_simulation_result = individual.simulation_result.clone();
 
@@ -278,7 +253,7 @@ fn simulate_and_average<'a>(individual: &Individual<'a>, settings: &'a ProblemSe
 }
 
 
-fn intermediate_recombination<'a>(individuals: Vec<Individual<'a>>, ind_id: i32) -> Individual<'a> {
+fn intermediate_recombination(individuals: Vec<Individual>, ind_id: i32) -> Individual {
     let mut prices = HashMap::new();
     let n_parents = individuals.len() as f64;
 
@@ -311,19 +286,12 @@ fn intermediate_recombination<'a>(individuals: Vec<Individual<'a>>, ind_id: i32)
         prices: PriceMatrix(prices),
         ind_id,
         fitness_score: 0.0,
-        simulation_result: SimulationResult {
-            event_history: vec![],
-            avg_regret: 0.0,
-            regret: 0.0,
-            customers: vec![],
-            avg_time_sold_at: 0.0,
-            n_sold: 0.0,
-            revenue: 0.0,
-        },
+        simulation_result: SimulationResult::default(),
     }
 }
 
-fn dominant_recombination<'a>(individuals: Vec<Individual<'a>>, ind_id: i32) -> Individual<'a> {
+#[allow(dead_code)]
+fn dominant_recombination(individuals: Vec<Individual>, ind_id: i32) -> Individual {
     let mut prices = HashMap::new();
     let mut rng = rand::thread_rng();
 
@@ -351,26 +319,18 @@ fn dominant_recombination<'a>(individuals: Vec<Individual<'a>>, ind_id: i32) ->
         prices: PriceMatrix(prices),
         ind_id,
         fitness_score: 0.0,
-        simulation_result: SimulationResult {
-            event_history: vec![],
-            avg_regret: 0.0,
-            regret: 0.0,
-            customers: vec![],
-            avg_time_sold_at: 0.0,
-            n_sold: 0.0,
-            revenue: 0.0,
-        },
+        simulation_result: SimulationResult::default(),
     }
 }
 
-pub fn evolve_pricing<'a>(
+pub fn evolve_pricing(
     run_id: i32,
-    settings: &'a ProblemSettings,
+    settings: &Arc<ProblemSettings>,
     algorithm_settings: &ESSettings,
-    mut writer: &mut csv::Writer<File>,
-) -> (Individual<'a>, Individual<'a>) {
+    writer: &mut csv::Writer<File>,
+) -> (Individual, Individual) {
     // population as a vector of individuals.
-    let mut population: Vec<Individual<'a>> = Vec::new();
+    let mut population: Vec<Individual> = Vec::new();
     let mut n_evals = 0;
 
     let mut params = algorithm_settings.clone();
@@ -385,7 +345,7 @@ pub fn evolve_pricing<'a>(
             ind_id,
             settings.n_visits as usize,
             settings.n_periods as usize,
-            settings.n_groups as usize,
+            settings.num_predicted_groups as usize,
             settings,
             algorithm_settings.fn_evals
         ));
@@ -412,12 +372,10 @@ pub fn evolve_pricing<'a>(
     let mut num_mutation_improved = 0;
     let mut num_recombination_improved = 0;
     for gen in 0..algorithm_settings.num_generations {
-        let mut offspring: Vec<Individual<'a>> = Vec::new();
+        let mut offspring: Vec<Individual> = Vec::new();
         let mut gen_best_solution = population[0].clone();
         let mut gen_best_score = 0.0;
 
-        let mut avg_score = 0.0;
-
         println!("Population best score: {}, {}", best_score, gen_best_solution.fitness_score);
 
         // generate offspring
@@ -432,8 +390,9 @@ pub fn evolve_pricing<'a>(
                 parents.push(population[parent_idx as usize].clone());
             }
             let mut offspring_individual = intermediate_recombination(parents.clone(), ind_id);
+            offspring_individual.prices.snap(&settings.price_grid, settings.max_price);
             
-            let result = simulate_and_average(&mut offspring_individual, settings, algorithm_settings.fn_evals);
+            let result = simulate_and_average(&offspring_individual, settings, algorithm_settings.fn_evals);
             offspring_individual.fitness_score = result.0;
             offspring_individual.simulation_result = result.1;
 
@@ -446,12 +405,10 @@ pub fn evolve_pricing<'a>(
             }
             
             ind_id += 1;
-            let mut mutated_offspring = mutate_solution(&offspring_individual, &params);
+            let mut mutated_offspring = mutate_solution(&offspring_individual, &params, settings);
 
             n_evals += 1;
-            let result = simulate_and_average(&mut mutated_offspring, settings, algorithm_settings.fn_evals);
-            avg_score += result.0;
-
+            let result = simulate_and_average(&mutated_offspring, settings, algorithm_settings.fn_evals);
             mutated_offspring.fitness_score = result.0;
             mutated_offspring.simulation_result = result.1;
 
@@ -474,7 +431,7 @@ pub fn evolve_pricing<'a>(
         // avg_score /= population.len() as f64;
         // Log generation stats to CSV
         log_population(
-            &mut writer,
+            writer,
             &population,
             gen,
             "population",
@@ -484,7 +441,7 @@ pub fn evolve_pricing<'a>(
             run_id,
         );
         log_population(
-            &mut writer,
+            writer,
             &offspring,
             gen,
             "offspring",
@@ -526,6 +483,8 @@ pub fn evolve_pricing<'a>(
                 } else {
                     params.mutation_strength
                 };
+                // steps much smaller than the price grid are rounded away and never change a price
+                params.mutation_strength = params.mutation_strength.max(settings.price_grid.step);
 
                 // Reset counter for next window
                 success_count = 0;
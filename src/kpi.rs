@@ -0,0 +1,155 @@
+use crate::simulation::ProblemSettings;
+
+/// Counters for one slice of a simulation run (a group or a period).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Kpis {
+    pub revenue: f64,
+    pub visits: usize, // number of price offers
+    pub n_sold: usize,
+    pub n_quit: usize,
+    pub misclassification_cost: f64, // regret accrued on customers whose predicted group is wrong
+}
+
+impl Kpis {
+    pub fn conversion(&self) -> f64 {
+        ratio(self.n_sold as f64, self.visits as f64)
+    }
+
+    pub fn quit_rate(&self) -> f64 {
+        ratio(self.n_quit as f64, self.visits as f64)
+    }
+
+    pub fn avg_price_paid(&self) -> f64 {
+        ratio(self.revenue, self.n_sold as f64)
+    }
+
+    pub fn visits_per_purchase(&self) -> f64 {
+        ratio(self.visits as f64, self.n_sold as f64)
+    }
+}
+
+fn ratio(numerator: f64, denominator: f64) -> f64 {
+    if denominator > 0.0 {
+        numerator / denominator
+    } else {
+        0.0
+    }
+}
+
+/// KPIs of a run broken down by true group, predicted group and period.
+#[derive(Debug, Clone, Default)]
+pub struct KpiBreakdown {
+    pub by_true_group: Vec<Kpis>,
+    pub by_predicted_group: Vec<Kpis>,
+    pub by_period: Vec<Kpis>,
+}
+
+impl KpiBreakdown {
+    pub fn new(settings: &ProblemSettings) -> Self {
+        Self {
+            by_true_group: vec![Kpis::default(); settings.n_groups as usize],
+            by_predicted_group: vec![Kpis::default(); settings.num_predicted_groups as usize],
+            by_period: vec![Kpis::default(); settings.n_periods as usize],
+        }
+    }
+
+    fn slices(&mut self, true_group: usize, predicted_group: usize, period: usize) -> [&mut Kpis; 3] {
+        let period = period.min(self.by_period.len() - 1);
+        [
+            &mut self.by_true_group[true_group],
+            &mut self.by_predicted_group[predicted_group],
+            &mut self.by_period[period],
+        ]
+    }
+
+    /// Records a price offer. `regret` is the wtp the offer left on the table,
+    /// it is booked as misclassification cost if the customer is misclassified.
+    pub fn record_visit(&mut self, true_group: usize, predicted_group: usize, period: usize, regret: f64) {
+        let misclassified = true_group != predicted_group;
+        for kpis in self.slices(true_group, predicted_group, period) {
+            kpis.visits += 1;
+            if misclassified {
+                kpis.misclassification_cost += regret;
+            }
+        }
+    }
+
+    pub fn record_sale(&mut self, true_group: usize, predicted_group: usize, period: usize, price: f64) {
+        for kpis in self.slices(true_group, predicted_group, period) {
+            kpis.n_sold += 1;
+            kpis.revenue += price;
+        }
+    }
+
+    pub fn record_quit(&mut self, true_group: usize, predicted_group: usize, period: usize) {
+        for kpis in self.slices(true_group, predicted_group, period) {
+            kpis.n_quit += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::tests::problem_settings;
+
+    #[test]
+    fn ratios_of_an_empty_slice_are_zero() {
+        let kpis = Kpis::default();
+        assert_eq!(kpis.conversion(), 0.0);
+        assert_eq!(kpis.quit_rate(), 0.0);
+        assert_eq!(kpis.avg_price_paid(), 0.0);
+        assert_eq!(kpis.visits_per_purchase(), 0.0);
+    }
+
+    #[test]
+    fn ratios_of_a_slice() {
+        let kpis = Kpis {
+            revenue: 300.0,
+            visits: 8,
+            n_sold: 2,
+            n_quit: 1,
+            misclassification_cost: 0.0,
+        };
+        assert_eq!(kpis.conversion(), 0.25);
+        assert_eq!(kpis.quit_rate(), 0.125);
+        assert_eq!(kpis.avg_price_paid(), 150.0);
+        assert_eq!(kpis.visits_per_purchase(), 4.0);
+    }
+
+    #[test]
+    fn offers_are_booked_on_every_slice() {
+        let mut breakdown = KpiBreakdown::new(&problem_settings());
+        // a customer of group 0 predicted as group 1 buys at 120, leaving 30 on the table
+        breakdown.record_visit(0, 1, 3, 30.0);
+        breakdown.record_sale(0, 1, 3, 120.0);
+        // a correctly classified customer quits
+        breakdown.record_visit(1, 1, 3, 200.0);
+        breakdown.record_quit(1, 1, 3);
+
+        let sold = Kpis {
+            revenue: 120.0,
+            visits: 1,
+            n_sold: 1,
+            n_quit: 0,
+            misclassification_cost: 30.0,
+        };
+        assert_eq!(breakdown.by_true_group[0], sold);
+        assert_eq!(breakdown.by_true_group[1].n_quit, 1);
+        assert_eq!(breakdown.by_true_group[1].misclassification_cost, 0.0);
+        assert_eq!(breakdown.by_predicted_group[0], Kpis::default());
+        assert_eq!(breakdown.by_predicted_group[1].visits, 2);
+        assert_eq!(breakdown.by_predicted_group[1].misclassification_cost, 30.0);
+        assert_eq!(breakdown.by_period[3].visits, 2);
+        assert_eq!(breakdown.by_period[3].revenue, 120.0);
+    }
+
+    #[test]
+    fn an_offer_at_the_horizon_counts_in_the_last_period() {
+        let settings = problem_settings();
+        let mut breakdown = KpiBreakdown::new(&settings);
+        breakdown.record_visit(0, 0, settings.n_periods as usize, 0.0);
+        assert_eq!(breakdown.by_period.len(), settings.n_periods as usize);
+        assert_eq!(breakdown.by_period.last().unwrap().visits, 1);
+    }
+}
@@ -1,8 +1,18 @@
+pub mod event_sink;
 pub mod evolution;
+pub mod kpi;
 pub mod logging;
 pub mod mab;
 pub mod network_formation;
+pub mod oracle;
 pub mod particle_swarm;
+pub mod price_grid;
 pub mod simulation;
+pub mod training;
 pub mod random_search;
-pub mod custom;
\ No newline at end of file
+pub mod reinforcement;
+pub mod clustering;
+pub mod contextual;
+pub mod continuous;
+pub mod custom;
+pub mod welfare;
\ No newline at end of file
@@ -1,9 +1,8 @@
-use crate::{evolution::{Adaptation, ESSettings, Individual, Selection}, simulation::{ProblemSettings, SimulationResult}};
+use crate::{evolution::{Adaptation, ESSettings, Individual, Selection}, kpi::{KpiBreakdown, Kpis}, oracle::OracleBenchmarks, simulation::{ProblemSettings, SimulationEvent}, training::TrainingReport, welfare::{PriceInequality, WelfareMetrics}};
 use std::fs::{self, File};
 
 pub fn log_individual(type_: &str, run_id: i32, best_solution: &Individual) {
     let file = std::fs::OpenOptions::new()
-        .write(true)
         .create(true)
         .append(true)
         .open("./results/price_matrix.csv")
@@ -30,33 +29,40 @@ pub fn log_individual(type_: &str, run_id: i32, best_solution: &Individual) {
     writer.flush().unwrap();
 }
 
-pub fn log_event_history(run_id: i32, simulation_result: &SimulationResult, settings: &ProblemSettings) {
+pub fn open_event_history() -> csv::Writer<File> {
     let file = std::fs::OpenOptions::new()
-        .write(true)
         .create(true)
         .append(true)
         .open("./results/event_history.csv")
         .unwrap();
-    let mut writer = csv::Writer::from_writer(file);
-    for event in simulation_result.event_history.iter() {
-        writer
-            .write_record(&[
-                run_id.to_string(),
-                event.t.to_string(),
-                event.event.to_string(),
-                event.customer.to_string(),
-                event.customer_wtp.to_string(),
-                event.customer_max_wtp.to_string(),
-                event.adjusted_wtp.to_string(),
-                event.actual_group.to_string(),
-                event.predicted_group.to_string(),
-                event.price.to_string(),
-                event.irp.to_string(),
-                event.erp.to_string(),
-                event.rp.to_string(),
-                settings.lambda.to_string(),
-            ])
-            .unwrap();
+    csv::Writer::from_writer(file)
+}
+
+pub fn write_event(writer: &mut csv::Writer<File>, run_id: i32, event: &SimulationEvent, loss_aversion: f64) {
+    writer
+        .write_record(&[
+            run_id.to_string(),
+            event.t.to_string(),
+            event.event.to_string(),
+            event.customer.to_string(),
+            event.customer_wtp.to_string(),
+            event.customer_max_wtp.to_string(),
+            event.adjusted_wtp.to_string(),
+            event.actual_group.to_string(),
+            event.predicted_group.to_string(),
+            event.price.to_string(),
+            event.irp.to_string(),
+            event.erp.to_string(),
+            event.rp.to_string(),
+            loss_aversion.to_string(),
+        ])
+        .unwrap();
+}
+
+pub fn log_event_history(run_id: i32, events: &[SimulationEvent], settings: &ProblemSettings) {
+    let mut writer = open_event_history();
+    for event in events.iter() {
+        write_event(&mut writer, run_id, event, settings.lambda);
     }
     writer.flush().unwrap();
 }
@@ -73,11 +79,11 @@ pub fn init_log() -> csv::Writer<File> {
     });
 
     let mut writer = csv::Writer::from_path("./results/price_matrix.csv").unwrap();
-    let header = vec!["type", "group", "visit", "t", "price"];
+    let header = ["type", "group", "visit", "t", "price"];
     writer.write_record(&header).unwrap();
 
     let mut writer = csv::Writer::from_path("./results/event_history.csv").unwrap();
-    let header = vec![
+    let header = [
         "run_id",
         "t",
         "event",
@@ -94,16 +100,17 @@ pub fn init_log() -> csv::Writer<File> {
         "loss_aversion",
     ];
     writer.write_record(&header).unwrap();
+    // flush the header now, the event sinks append to the same file through their own handles
+    writer.flush().unwrap();
 
     
     
-    return writer;
+    writer
 }
 
 pub fn init_log_es() -> csv::Writer<File> {
 
     let file = std::fs::OpenOptions::new()
-        .write(true)
         .create(true)
         .append(true)
         .open("./results/evolution_log.csv")
@@ -111,7 +118,7 @@ pub fn init_log_es() -> csv::Writer<File> {
 
     let mut writer = csv::Writer::from_writer(file);
     writer
-        .write_record(&[
+        .write_record([
             "run_id",
             "generation",
             "n_evals",
@@ -129,7 +136,7 @@ pub fn init_log_es() -> csv::Writer<File> {
             "loss_aversion"
         ])
         .unwrap();
-    return writer
+    writer
 }
 
 
@@ -138,14 +145,13 @@ pub fn init_log_pso() -> csv::Writer<File> {
         println!("Error removing file: {}", e);
     });
     let file = std::fs::OpenOptions::new()
-        .write(true)
         .create(true)
         .append(true)
         .open("./results/pso_log.csv")
         .unwrap();
     let mut writer = csv::Writer::from_writer(file);
     writer
-        .write_record(&[
+        .write_record([
             "run_id",
             "num_evals",
             "particle_id",
@@ -159,7 +165,223 @@ pub fn init_log_pso() -> csv::Writer<File> {
             "velocity_norm",
         ])
         .unwrap();
-    return writer;
+    writer
+}
+
+pub fn init_log_kpis() -> csv::Writer<File> {
+    fs::remove_file("./results/kpis.csv").unwrap_or_else(|e| {
+        println!("Error removing file: {}", e);
+    });
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open("./results/kpis.csv")
+        .unwrap();
+    let mut writer = csv::Writer::from_writer(file);
+    writer
+        .write_record([
+            "run_id",
+            "dimension",
+            "key",
+            "revenue",
+            "visits",
+            "n_sold",
+            "n_quit",
+            "conversion",
+            "quit_rate",
+            "avg_price_paid",
+            "visits_per_purchase",
+            "misclassification_cost",
+        ])
+        .unwrap();
+    writer
+}
+
+pub fn init_log_training() -> csv::Writer<File> {
+    fs::remove_file("./results/learning_curve.csv").unwrap_or_else(|e| {
+        println!("Error removing file: {}", e);
+    });
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open("./results/learning_curve.csv")
+        .unwrap();
+    let mut writer = csv::Writer::from_writer(file);
+    writer
+        .write_record(["policy", "episode", "phase", "revenue", "regret", "n_sold", "conversion"])
+        .unwrap();
+    writer
+}
+
+pub fn init_log_q_table() -> csv::Writer<File> {
+    fs::remove_file("./results/q_table.csv").unwrap_or_else(|e| {
+        println!("Error removing file: {}", e);
+    });
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open("./results/q_table.csv")
+        .unwrap();
+    let mut writer = csv::Writer::from_writer(file);
+    writer
+        .write_record(["strategy", "group", "visit", "t", "reference_bin", "best_price", "value", "num_updates"])
+        .unwrap();
+    writer
+}
+
+pub fn log_learning_curve(writer: &mut csv::Writer<File>, policy: &str, report: &TrainingReport) {
+    for point in report.curve.iter() {
+        writer
+            .write_record(&[
+                policy.to_string(),
+                point.episode.to_string(),
+                point.phase.to_string(),
+                point.revenue.to_string(),
+                point.regret.to_string(),
+                point.n_sold.to_string(),
+                point.conversion.to_string(),
+            ])
+            .unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+pub fn log_kpis(writer: &mut csv::Writer<File>, run_id: i32, kpis: &KpiBreakdown) {
+    let dimensions: [(&str, &Vec<Kpis>); 3] = [
+        ("true_group", &kpis.by_true_group),
+        ("predicted_group", &kpis.by_predicted_group),
+        ("period", &kpis.by_period),
+    ];
+    for (dimension, slices) in dimensions {
+        for (key, slice) in slices.iter().enumerate() {
+            writer
+                .write_record(&[
+                    run_id.to_string(),
+                    dimension.to_string(),
+                    key.to_string(),
+                    slice.revenue.to_string(),
+                    slice.visits.to_string(),
+                    slice.n_sold.to_string(),
+                    slice.n_quit.to_string(),
+                    slice.conversion().to_string(),
+                    slice.quit_rate().to_string(),
+                    slice.avg_price_paid().to_string(),
+                    slice.visits_per_purchase().to_string(),
+                    slice.misclassification_cost.to_string(),
+                ])
+                .unwrap();
+        }
+    }
+    writer.flush().unwrap();
+}
+
+pub fn init_log_welfare() -> csv::Writer<File> {
+    fs::remove_file("./results/welfare.csv").unwrap_or_else(|e| {
+        println!("Error removing file: {}", e);
+    });
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open("./results/welfare.csv")
+        .unwrap();
+    let mut writer = csv::Writer::from_writer(file);
+    writer
+        .write_record([
+            "run_id",
+            "scope",
+            "consumer_surplus",
+            "producer_surplus",
+            "deadweight_loss",
+            "total_welfare",
+            "price_dispersion",
+            "n_buyers",
+            "mean_price",
+            "gini",
+            "coefficient_of_variation",
+        ])
+        .unwrap();
+    writer
+}
+
+/// Writes one `all` row with the welfare totals followed by one row per true
+/// group with the inequality of the prices paid in that group.
+pub fn log_welfare(writer: &mut csv::Writer<File>, run_id: i32, welfare: &WelfareMetrics) {
+    let inequality_fields = |inequality: &PriceInequality| {
+        [
+            inequality.n_buyers.to_string(),
+            inequality.mean_price.to_string(),
+            inequality.gini.to_string(),
+            inequality.coefficient_of_variation.to_string(),
+        ]
+    };
+
+    let mut record = vec![
+        run_id.to_string(),
+        "all".to_string(),
+        welfare.consumer_surplus.to_string(),
+        welfare.producer_surplus.to_string(),
+        welfare.deadweight_loss.to_string(),
+        welfare.total_welfare.to_string(),
+        welfare.price_dispersion.to_string(),
+    ];
+    record.extend(inequality_fields(&welfare.inequality));
+    writer.write_record(&record).unwrap();
+
+    for (group, inequality) in welfare.inequality_by_group.iter().enumerate() {
+        let mut record = vec![run_id.to_string(), format!("group_{}", group)];
+        record.extend(std::iter::repeat_n(String::new(), 5));
+        record.extend(inequality_fields(inequality));
+        writer.write_record(&record).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+pub fn init_log_oracle() -> csv::Writer<File> {
+    fs::remove_file("./results/oracle_gaps.csv").unwrap_or_else(|e| {
+        println!("Error removing file: {}", e);
+    });
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open("./results/oracle_gaps.csv")
+        .unwrap();
+    let mut writer = csv::Writer::from_writer(file);
+    writer
+        .write_record([
+            "policy",
+            "revenue",
+            "first_degree",
+            "per_true_group",
+            "per_predicted_group",
+            "gap_first_degree",
+            "gap_per_true_group",
+            "gap_per_predicted_group",
+            "gap_first_degree_pct",
+            "gap_per_true_group_pct",
+            "gap_per_predicted_group_pct",
+        ])
+        .unwrap();
+    writer
+}
+
+pub fn log_oracle_gaps(writer: &mut csv::Writer<File>, policy: &str, revenue: f64, benchmarks: &OracleBenchmarks) {
+    let gaps = benchmarks.gaps(revenue);
+    writer
+        .write_record(&[
+            policy.to_string(),
+            revenue.to_string(),
+            benchmarks.first_degree.to_string(),
+            benchmarks.per_true_group.to_string(),
+            benchmarks.per_predicted_group.to_string(),
+            gaps.first_degree.to_string(),
+            gaps.per_true_group.to_string(),
+            gaps.per_predicted_group.to_string(),
+            gaps.first_degree_pct.to_string(),
+            gaps.per_true_group_pct.to_string(),
+            gaps.per_predicted_group_pct.to_string(),
+        ])
+        .unwrap();
+    writer.flush().unwrap();
 }
 
 pub fn init_log_mab() -> (csv::Writer<File>, csv::Writer<File>) {
@@ -171,34 +393,50 @@ pub fn init_log_mab() -> (csv::Writer<File>, csv::Writer<File>) {
     });
 
     let file = std::fs::OpenOptions::new()
-            .write(true)
             .create(true)
             .append(true)
             .open("./results/mab_arms.csv")
             .unwrap();
     let mut arms_writer = csv::Writer::from_writer(file);
     arms_writer
-        .write_record(&["config_id", "epsilon", "strategy", "t", "group", "best_price", "num_pulls"])
+        .write_record([
+            "config_id",
+            "epsilon",
+            "strategy",
+            "t",
+            "group",
+            "visit",
+            "best_price",
+            "num_pulls",
+            "beta_alpha",
+            "beta_beta",
+            "posterior_mean",
+            "posterior_std",
+            "lower_bound",
+            "upper_bound",
+            "n_active",
+            "identified",
+        ])
         .unwrap();
 
     let file = std::fs::OpenOptions::new()
-        .write(true)
         .create(true)
         .append(true)
         .open("./results/mab_log.csv")
         .unwrap();
     let mut mab_log_writer = csv::Writer::from_writer(file);
     mab_log_writer
-        .write_record(&["config_id", "run_id", "t", "group", "visit", "price", "reward", "last_action"])
+        .write_record(["config_id", "run_id", "t", "group", "visit", "price", "reward", "last_action"])
         .unwrap();
     return (arms_writer, mab_log_writer);
 }
 
 
 
-pub fn log_population<'a>(
+#[allow(clippy::too_many_arguments)]
+pub fn log_population(
     writer: &mut csv::Writer<std::fs::File>,
-    population: &Vec<Individual<'a>>,
+    population: &[Individual],
     generation: i32,
     type_: &str,
     algorithm_settings: &ESSettings,
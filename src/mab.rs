@@ -1,8 +1,12 @@
-use std::{collections::HashMap, fs::File};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+};
 
 use rand::Rng;
+use rand_distr::{Beta, Normal};
 
-use crate::simulation::ProblemSettings;
+use crate::simulation::{CustomerObservations, ProblemSettings, SimulationResult};
 
 /// Strategy to use for multi-armed bandit exploration
 #[derive(Clone, Copy, PartialEq)]
@@ -10,6 +14,17 @@ pub enum MABStrategy {
     EpsilonGreedy,
     UCB,
     DecayingEpsilonGreedy,
+    ThompsonBeta,     // Beta-Bernoulli posterior on the purchase probability, reward = price * p
+    ThompsonGaussian, // Gaussian posterior on the revenue of an offer
+    SlidingWindowUCB, // UCB over the last `window_size` offers of a group and period
+    DiscountedUCB,    // UCB over rewards discounted by `discount` per offer
+    EXP3,             // adversarial bandit with exploration rate `exp3_gamma`
+    EXP3S,            // EXP3 with weight sharing `exp3s_alpha` to track a switching best arm
+    ChangeDetectionUCB, // UCB restarted when a Page-Hinkley test detects a change
+    HierarchicalThompson, // Beta-Bernoulli Thompson sampling with price, group and period levels
+    SuccessiveElimination, // round-robin over the arms that are not confidently dominated yet
+    SuccessiveHalving,     // halves the arms of a cell every time `halving_budget` is spent
+    LUCB,                  // samples the empirical best arm and its strongest challenger until they separate
 }
 
 impl std::fmt::Display for MABStrategy {
@@ -18,41 +33,256 @@ impl std::fmt::Display for MABStrategy {
             MABStrategy::EpsilonGreedy => write!(f, "EpsilonGreedy"),
             MABStrategy::UCB => write!(f, "UCB"),
             MABStrategy::DecayingEpsilonGreedy => write!(f, "DecayingEpsilonGreedy"),
+            MABStrategy::ThompsonBeta => write!(f, "ThompsonBeta"),
+            MABStrategy::ThompsonGaussian => write!(f, "ThompsonGaussian"),
+            MABStrategy::SlidingWindowUCB => write!(f, "SlidingWindowUCB"),
+            MABStrategy::DiscountedUCB => write!(f, "DiscountedUCB"),
+            MABStrategy::EXP3 => write!(f, "EXP3"),
+            MABStrategy::EXP3S => write!(f, "EXP3S"),
+            MABStrategy::ChangeDetectionUCB => write!(f, "ChangeDetectionUCB"),
+            MABStrategy::HierarchicalThompson => write!(f, "HierarchicalThompson"),
+            MABStrategy::SuccessiveElimination => write!(f, "SuccessiveElimination"),
+            MABStrategy::SuccessiveHalving => write!(f, "SuccessiveHalving"),
+            MABStrategy::LUCB => write!(f, "LUCB"),
         }
     }
 }
 
+impl std::str::FromStr for MABStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let strategies = [
+            MABStrategy::EpsilonGreedy,
+            MABStrategy::UCB,
+            MABStrategy::DecayingEpsilonGreedy,
+            MABStrategy::ThompsonBeta,
+            MABStrategy::ThompsonGaussian,
+            MABStrategy::SlidingWindowUCB,
+            MABStrategy::DiscountedUCB,
+            MABStrategy::EXP3,
+            MABStrategy::EXP3S,
+            MABStrategy::ChangeDetectionUCB,
+            MABStrategy::HierarchicalThompson,
+            MABStrategy::SuccessiveElimination,
+            MABStrategy::SuccessiveHalving,
+            MABStrategy::LUCB,
+        ];
+        strategies
+            .into_iter()
+            .find(|strategy| strategy.to_string() == s)
+            .ok_or_else(|| format!("unknown MAB strategy {}", s))
+    }
+}
+
+/// Dimensions of an offer the MAB keeps separate arms for. Dimensions that are
+/// left out share their arms, e.g. without `visit` all visits of a group and
+/// period learn the same prices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ArmKey {
+    pub group: bool,
+    pub visit: bool,                  // offers the customer saw before, so e.g. a second visit can get its own price
+    pub period_bucket: Option<usize>, // periods per bucket, None = one bucket for the whole horizon
+}
+
+impl ArmKey {
+    /// One set of arms per (predicted group, period).
+    pub fn group_period() -> Self {
+        Self {
+            group: true,
+            visit: false,
+            period_bucket: Some(1),
+        }
+    }
+}
+
+/// Limits on exploration before a policy can face real customers. Any offer
+/// other than the current best arm of its cell counts as exploratory. The
+/// revenue floor holds within each episode: the revenue of the episode so far
+/// stays above `1 - conservative_alpha` times what the baseline policy is
+/// expected to earn on the same offers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SafeExploration {
+    pub price_band: Option<f64>,   // exploratory prices stay within this distance of the best arm
+    pub conservative_alpha: Option<f64>, // revenue of an episode may fall at most this share below the baseline
+    pub baseline_price: Option<f64>, // price of the baseline policy, None = the best arm of each cell
+    pub max_explorations_per_period: Option<usize>, // exploratory offers per period of a run
+}
+
 #[derive(Clone)]
 pub struct Arm {
-    price: i32,
+    price: f64,
     average_reward: f64,
     num_pulls: usize,
+    num_sales: usize, // pulls with a positive reward
+    m2: f64,          // sum of squared deviations from the average reward
+    num_pending: usize, // offers made at this arm whose outcome is not known yet
+    eliminated: bool,   // dropped by a best-arm identification strategy
+    discounted_pulls: f64,
+    discounted_reward: f64,
+    weight: f64, // EXP3 weight, renormalised so the largest weight of a group and period is 1
+    ph_cumulative: f64, // Page-Hinkley cumulative deviation of the normalised reward
+    ph_min: f64,
+    prior_pulls: usize, // warm-start pseudo-offers sold at the arm price, part of num_pulls and average_reward
 }
 
 impl Arm {
-    pub fn new(id: usize, price: i32) -> Self {
+    pub fn new(price: f64) -> Self {
         Self {
             price,
             average_reward: 0.0,
             num_pulls: 0,
+            num_sales: 0,
+            m2: 0.0,
+            num_pending: 0,
+            eliminated: false,
+            discounted_pulls: 0.0,
+            discounted_reward: 0.0,
+            weight: 1.0,
+            ph_cumulative: 0.0,
+            ph_min: 0.0,
+            prior_pulls: 0,
+        }
+    }
+
+    fn update(&mut self, reward: f64) {
+        self.num_pulls += 1;
+        self.discounted_pulls += 1.0;
+        self.discounted_reward += reward;
+        if reward > 0.0 {
+            self.num_sales += 1;
+        }
+        let delta = reward - self.average_reward;
+        self.average_reward += delta / self.num_pulls as f64;
+        self.m2 += delta * (reward - self.average_reward);
+    }
+
+    // Pseudo-offers sold at the arm price, they move the value estimate but are
+    // not sales: posteriors, conversion rates and confidence bounds ignore them
+    fn add_prior(&mut self, pulls: usize) {
+        let total = (self.num_pulls + pulls) as f64;
+        let delta = self.price - self.average_reward;
+        self.m2 += delta * delta * self.num_pulls as f64 * pulls as f64 / total;
+        self.average_reward += delta * pulls as f64 / total;
+        self.num_pulls += pulls;
+        self.discounted_pulls += pulls as f64;
+        self.discounted_reward += self.price * pulls as f64;
+        self.prior_pulls += pulls;
+    }
+
+    /// Offers actually made at this arm, without warm-start pseudo-offers.
+    pub fn observed_pulls(&self) -> usize {
+        self.num_pulls - self.prior_pulls
+    }
+
+    /// Mean reward of the offers actually made at this arm.
+    pub fn observed_mean(&self) -> f64 {
+        match self.observed_pulls() {
+            0 => 0.0,
+            n => (self.average_reward * self.num_pulls as f64 - self.price * self.prior_pulls as f64) / n as f64,
         }
     }
+
+    /// Parameters of the Beta(1, 1) prior updated with the sales of this arm.
+    pub fn beta_posterior(&self) -> (f64, f64) {
+        (
+            1.0 + self.num_sales as f64,
+            1.0 + (self.observed_pulls() - self.num_sales) as f64,
+        )
+    }
+
+    /// Mean and standard deviation of the posterior of the mean revenue, with a
+    /// N(0, prior_std^2) prior and the sample variance as the noise variance.
+    pub fn gaussian_posterior(&self, prior_std: f64) -> (f64, f64) {
+        let noise_var = if self.num_pulls > 1 {
+            (self.m2 / (self.num_pulls - 1) as f64).max(1.0)
+        } else {
+            (prior_std / 2.0).powi(2)
+        };
+        let precision = 1.0 / prior_std.powi(2) + self.num_pulls as f64 / noise_var;
+        let mean = (self.average_reward * self.num_pulls as f64 / noise_var) / precision;
+        (mean, precision.recip().sqrt())
+    }
+
+    /// Share of the offers of this arm that were bought, with Laplace smoothing
+    /// so that a few lucky sales do not make an arm look certain.
+    pub fn conversion_rate(&self) -> f64 {
+        (self.num_sales as f64 + 1.0) / (self.observed_pulls() as f64 + 2.0)
+    }
+
+    fn discounted_average(&self) -> f64 {
+        if self.discounted_pulls > 0.0 {
+            self.discounted_reward / self.discounted_pulls
+        } else {
+            0.0
+        }
+    }
+
+    /// Feeds the reward divided by `scale` to a Page-Hinkley test for a drop of
+    /// the mean and returns whether the statistic crossed `threshold`.
+    fn detect_change(&mut self, reward: f64, scale: f64, delta: f64, threshold: f64) -> bool {
+        if self.num_pulls < 2 {
+            return false;
+        }
+        self.ph_cumulative += (self.average_reward - reward) / scale - delta;
+        self.ph_min = self.ph_min.min(self.ph_cumulative);
+        self.ph_cumulative - self.ph_min > threshold
+    }
+}
+
+// Arm an offer was made at, kept until the outcome of the offer is known
+#[derive(Clone, Copy, Debug, Default)]
+struct Offer {
+    group_id: usize,
+    period: usize,
+    arm_id: usize,
+    probability: f64, // probability of playing the arm when it was chosen, for the EXP3 importance weight
+    explored: bool,   // away from the baseline arm, its revenue counts towards the conservative constraint
 }
 
 pub struct MAB<'a> {
     num_arms: usize,
-    arms: HashMap<usize, HashMap<usize, HashMap<usize, Arm>>>, // group_id -> time_period -> arm_id (price)
-    best_arms: HashMap<usize, HashMap<usize, usize>>, // group_id -> time_period -> arm_id
-    pub best_rewards: HashMap<usize, HashMap<usize, f64>>, // group_id -> time_period -> reward
+    // the outer keys are the segment (group and visit as configured by `arm_key`) and the period bucket
+    arms: HashMap<usize, HashMap<usize, HashMap<usize, Arm>>>, // segment -> bucket -> arm_id (price)
+    best_arms: HashMap<usize, HashMap<usize, usize>>, // segment -> bucket -> arm_id
+    pub best_rewards: HashMap<usize, HashMap<usize, f64>>, // segment -> bucket -> reward
+    arm_key: ArmKey,
+    n_visit_keys: usize,
+    safety: SafeExploration,
+    // conservative constraint of the current episode, offers at the baseline arm count at its mean
+    explored_revenue: f64,  // realized revenue of the offers away from the baseline arm
+    explored_baseline: f64, // expected revenue of the baseline arm on those offers
+    baseline_played: f64,   // expected revenue of the offers at the baseline arm
+    explorations: HashMap<usize, usize>, // period -> exploratory offers in the current run
     epsilon: f64,
     final_epsilon: f64,
     n_runs: usize,
     ucb_param: f64,
     strategy: MABStrategy,
+    prior_std: f64, // prior standard deviation of the revenue for Gaussian Thompson sampling
+    conversion_aware: bool,
+    reward_scale: f64, // rewards are divided by this before they are compared
+    window_size: usize,
+    discount: f64,
+    exp3_gamma: f64,
+    exp3s_alpha: f64,
+    change_delta: f64,
+    change_threshold: f64,
+    recent: HashMap<(usize, usize), VecDeque<(usize, f64)>>, // (group_id, period) -> last (arm_id, reward)
+    pooling_bandwidth: f64,
+    prior_strength: f64,
+    price_totals: HashMap<(usize, usize), (usize, usize)>, // (group_id, arm_id) -> (sales, pulls) over all periods
+    confidence_delta: f64,
+    halving_budget: usize,
+    halving_targets: HashMap<(usize, usize), usize>, // (group_id, period) -> pulls per arm that end the round
+    pub n_restarts: usize,
     arms_per_group: usize,
-    action_space: Vec<i32>,
+    action_space: Vec<f64>, // grid prices, arms are keyed by their index
     writer: &'a mut csv::Writer<File>,
     last_action: String,
+    last_offer: Offer, // arm of the last get_price
+    frozen: bool,
+    pending: HashMap<usize, Offer>, // offer_id -> arm, until the outcome is known
     pub run_id: usize,
     pub config_id: usize,
 }
@@ -66,6 +296,30 @@ pub struct MABSettings {
     pub n_runs: usize,
     pub ucb_param: f64,
     pub strategy: MABStrategy,
+    pub arm_key: ArmKey,
+    pub safety: SafeExploration,
+    pub conversion_aware: bool,  // value arms by price * estimated purchase probability
+    pub normalize_rewards: bool, // divide rewards by max_price so they lie in [0, 1]
+    pub window_size: usize,      // offers remembered per group and period by SlidingWindowUCB
+    pub discount: f64,           // per-offer discount factor of DiscountedUCB
+    pub exp3_gamma: f64,         // exploration rate of EXP3 and EXP3S
+    pub exp3s_alpha: f64,        // weight sharing of EXP3S
+    pub change_delta: f64,       // tolerated drop of the normalised reward before it counts as change
+    pub change_threshold: f64,   // Page-Hinkley threshold that restarts a group and period
+    pub pooling_bandwidth: f64,  // periods over which HierarchicalThompson borrows observations
+    pub prior_strength: f64,     // pseudo-observations of each prior level of HierarchicalThompson
+    pub confidence_delta: f64,   // error probability of the best-arm identification strategies
+    pub halving_budget: usize,   // offers per group and period that SuccessiveHalving plans to spend
+}
+
+impl MABSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        // the posterior of a cell without offers is its prior alone
+        if self.prior_strength <= 0.0 {
+            return Err(format!("prior_strength is {}, expected a positive value", self.prior_strength));
+        }
+        Ok(())
+    }
 }
 
 impl<'a> MAB<'a> {
@@ -76,36 +330,33 @@ impl<'a> MAB<'a> {
         run_id: usize,
         config_id: usize,
     ) -> Self {
-        let mut id = 0;
-
-        let mut action_space = Vec::new();
-
-        for i in 0..algorithm_settings.arms_per_group {
-            let price =
-                algorithm_settings.min_price + (algorithm_settings.max_price - algorithm_settings.min_price) * (i as f64 / (algorithm_settings.arms_per_group - 1) as f64);
-            action_space.push(price as i32);
-        }
-        println!("action_space: {:?}", action_space);
+        let mut mab = Self::empty(settings, algorithm_settings, writer, run_id, config_id);
+        let action_space = &mab.action_space;
+        let arms_per_group = action_space.len();
 
         let mut arms = HashMap::new();
         let mut best_rewards = HashMap::new();
         let mut best_arms = HashMap::new();
 
-        for group_id in 0..(settings.num_predicted_groups as usize) {
+        let n_group_keys = if mab.arm_key.group { settings.num_predicted_groups as usize } else { 1 };
+        let n_buckets = match mab.arm_key.period_bucket {
+            Some(bucket) => (settings.n_periods as usize).div_ceil(bucket),
+            None => 1,
+        };
+
+        for group_id in 0..(n_group_keys * mab.n_visit_keys) {
             let mut group_arms = HashMap::new();
             let mut best_group_arms = HashMap::new();
             let mut best_group_rewards = HashMap::new();
 
-            for period_id in 0..(settings.n_periods as usize) {
+            for period_id in 0..n_buckets {
                 let mut arms = HashMap::new();
-                for arm_id in 0..algorithm_settings.arms_per_group {
-                    let price = action_space[arm_id];
-                    arms.insert(price as usize, Arm::new(id, price as i32));
-                    id += 1;
+                for (arm_id, price) in action_space.iter().enumerate() {
+                    arms.insert(arm_id, Arm::new(*price));
                 }
 
-                let random_arm = action_space[rand::thread_rng().gen_range(0..algorithm_settings.arms_per_group)];
-                best_group_arms.insert(period_id, random_arm as usize);
+                let random_arm = rand::thread_rng().gen_range(0..arms_per_group);
+                best_group_arms.insert(period_id, random_arm);
                 best_group_rewards.insert(period_id, 0.0);
                 group_arms.insert(period_id, arms);
             }
@@ -114,29 +365,194 @@ impl<'a> MAB<'a> {
             best_rewards.insert(group_id, best_group_rewards);
             best_arms.insert(group_id, best_group_arms);
         }
+        mab.num_arms = n_group_keys * mab.n_visit_keys * n_buckets * arms_per_group;
+        mab.arms = arms;
+        mab.best_arms = best_arms;
+        mab.best_rewards = best_rewards;
+        mab
+    }
+
+    // Bandit without arms, `new` and `load` fill them in
+    fn empty(
+        settings: &ProblemSettings,
+        algorithm_settings: &MABSettings,
+        writer: &'a mut csv::Writer<File>,
+        run_id: usize,
+        config_id: usize,
+    ) -> Self {
+        algorithm_settings.validate().unwrap();
+        let action_space = settings.price_grid.prices(
+            algorithm_settings.min_price,
+            algorithm_settings.max_price,
+            algorithm_settings.arms_per_group,
+        );
+        let arm_key = algorithm_settings.arm_key;
         Self {
-            num_arms: (settings.num_predicted_groups as usize) * algorithm_settings.arms_per_group,
-            arms: arms,
-            best_arms,
-            best_rewards,
+            num_arms: 0,
+            arms: HashMap::new(),
+            best_arms: HashMap::new(),
+            best_rewards: HashMap::new(),
+            arm_key,
+            n_visit_keys: if arm_key.visit { settings.n_visits as usize } else { 1 },
+            safety: algorithm_settings.safety,
+            explored_revenue: 0.0,
+            explored_baseline: 0.0,
+            baseline_played: 0.0,
+            explorations: HashMap::new(),
             epsilon: algorithm_settings.epsilon,
             final_epsilon: algorithm_settings.final_epsilon,
             n_runs: algorithm_settings.n_runs,
             ucb_param: algorithm_settings.ucb_param,
             strategy: algorithm_settings.strategy,
-            arms_per_group: algorithm_settings.arms_per_group,
+            prior_std: algorithm_settings.max_price,
+            conversion_aware: algorithm_settings.conversion_aware,
+            reward_scale: if algorithm_settings.normalize_rewards {
+                algorithm_settings.max_price
+            } else {
+                1.0
+            },
+            window_size: algorithm_settings.window_size,
+            discount: algorithm_settings.discount,
+            exp3_gamma: algorithm_settings.exp3_gamma,
+            exp3s_alpha: algorithm_settings.exp3s_alpha,
+            change_delta: algorithm_settings.change_delta,
+            change_threshold: algorithm_settings.change_threshold,
+            recent: HashMap::new(),
+            pooling_bandwidth: algorithm_settings.pooling_bandwidth,
+            prior_strength: algorithm_settings.prior_strength,
+            price_totals: HashMap::new(),
+            confidence_delta: algorithm_settings.confidence_delta,
+            halving_budget: algorithm_settings.halving_budget,
+            halving_targets: HashMap::new(),
+            n_restarts: 0,
+            arms_per_group: action_space.len(),
             action_space,
             writer,
             last_action: "".to_string(),
+            last_offer: Offer::default(),
+            frozen: false,
+            pending: HashMap::new(),
             run_id: run_id,
             config_id: config_id,
         }
     }
 
-    pub fn random_action(&self) -> i32 {
+    /// Total number of arms over all segments and period buckets.
+    pub fn num_arms(&self) -> usize {
+        self.num_arms
+    }
+
+    // Segment and period bucket whose arms price an offer
+    fn cell(&self, group_id: usize, visit: usize, period: usize) -> (usize, usize) {
+        let group_key = if self.arm_key.group { group_id } else { 0 };
+        let visit_key = if self.arm_key.visit { visit.min(self.n_visit_keys - 1) } else { 0 };
+        // an event exactly at the horizon belongs to the last bucket
+        let n_buckets = self.arms[&0].len();
+        let bucket = self.arm_key.period_bucket.map_or(0, |bucket| (period / bucket).min(n_buckets - 1));
+        (group_key * self.n_visit_keys + visit_key, bucket)
+    }
+
+    /// Arm whose grid price is closest to `price`.
+    pub fn nearest_arm(&self, price: f64) -> usize {
+        (0..self.action_space.len())
+            .min_by(|a, b| {
+                let distance = |arm_id: &usize| (self.action_space[*arm_id] - price).abs();
+                distance(a).partial_cmp(&distance(b)).unwrap()
+            })
+            .unwrap()
+    }
+
+    // Arm of a cell that the baseline policy would offer
+    fn baseline_arm(&self, group_id: usize, period: usize) -> usize {
+        match self.safety.baseline_price {
+            Some(price) => self.nearest_arm(price),
+            None => self.best_arms[&group_id][&period],
+        }
+    }
+
+    // Replaces an exploratory arm that breaks one of the safety constraints
+    fn make_safe(&mut self, group_id: usize, period: usize, raw_period: usize, arm_id: usize) -> usize {
+        let best = self.best_arms[&group_id][&period];
+        let mut arm_id = arm_id;
+        if arm_id != best {
+            arm_id = self.limit_exploration(best, raw_period, arm_id);
+        }
+
+        let baseline = self.baseline_arm(group_id, period);
+        let baseline_mean = self.arms[&group_id][&period][&baseline].average_reward;
+        if arm_id != baseline {
+            if let Some(alpha) = self.safety.conservative_alpha {
+                // CLUCB check: the pessimistic revenue of the episode with this offer
+                // must stay above (1 - alpha) times the baseline on the same offers
+                let arm = &self.arms[&group_id][&period][&arm_id];
+                let total_pulls: usize = self.arms[&group_id][&period].values().map(|arm| arm.observed_pulls()).sum();
+                let lower_bound = if arm.observed_pulls() == 0 {
+                    0.0
+                } else {
+                    // Hoeffding bound, the reward of an offer lies in [0, price]
+                    let log_term = (total_pulls.max(2) as f64).ln() / (2.0 * arm.observed_pulls() as f64);
+                    (arm.observed_mean() - arm.price * log_term.sqrt()).max(0.0)
+                };
+                let pessimistic = self.explored_revenue + lower_bound + self.baseline_played;
+                let floor = (1.0 - alpha) * (self.explored_baseline + baseline_mean + self.baseline_played);
+                if pessimistic < floor {
+                    self.last_action = "baseline".to_string();
+                    arm_id = baseline;
+                }
+            }
+        }
+        if arm_id == baseline {
+            self.baseline_played += baseline_mean;
+        } else {
+            self.explored_baseline += baseline_mean;
+        }
+
+        if arm_id != best {
+            *self.explorations.entry(raw_period).or_insert(0) += 1;
+        }
+        arm_id
+    }
+
+    // Price band and per-period cap of an exploratory arm
+    fn limit_exploration(&mut self, best: usize, raw_period: usize, arm_id: usize) -> usize {
+        if let Some(cap) = self.safety.max_explorations_per_period {
+            if self.explorations.get(&raw_period).copied().unwrap_or(0) >= cap {
+                self.last_action = "capped".to_string();
+                return best;
+            }
+        }
+
+        let mut arm_id = arm_id;
+        if let Some(band) = self.safety.price_band {
+            let (price, best_price) = (self.action_space[arm_id], self.action_space[best]);
+            if (price - best_price).abs() > band {
+                // closest grid price inside the band
+                arm_id = (0..self.action_space.len())
+                    .filter(|other| (self.action_space[*other] - best_price).abs() <= band)
+                    .min_by(|a, b| {
+                        let distance = |other: &usize| (self.action_space[*other] - price).abs();
+                        distance(a).partial_cmp(&distance(b)).unwrap()
+                    })
+                    .unwrap_or(best);
+                self.last_action = "banded".to_string();
+            }
+        }
+        arm_id
+    }
+
+    pub fn random_action(&self) -> usize {
         let random_offset = rand::thread_rng().gen_range(0..(self.arms_per_group - 1));
         // println!("random_offset: {}", self.action_space[random_offset]);
-        return self.action_space[random_offset];
+        return random_offset;
+    }
+
+    // Estimated expected reward of an offer at this arm
+    fn arm_value(&self, arm: &Arm) -> f64 {
+        if self.conversion_aware {
+            arm.price * arm.conversion_rate() / self.reward_scale
+        } else {
+            arm.average_reward / self.reward_scale
+        }
     }
 
     // Calculate UCB score for an arm
@@ -145,18 +561,25 @@ impl<'a> MAB<'a> {
             return f64::INFINITY; // Ensure arms with zero pulls are tried first
         }
 
-        let exploitation = arm.average_reward;
-        let exploration = (self.ucb_param * (total_pulls as f64).ln() / arm.num_pulls as f64).sqrt();
+        // offers still waiting for feedback already shrink the bonus, so that an arm
+        // is not offered over and over before its first outcomes arrive
+        let exploration =
+            (self.ucb_param * (total_pulls as f64).ln() / (arm.num_pulls + arm.num_pending) as f64).sqrt();
+        if self.conversion_aware {
+            // optimism on the purchase probability, which is bounded by 1
+            return arm.price * (arm.conversion_rate() + exploration).min(1.0) / self.reward_scale;
+        }
 
+        let exploitation = self.arm_value(arm);
         exploitation + exploration
     }
 
     // Select the best arm according to UCB strategy
-    fn select_ucb_arm(&self, group_id: usize, period: usize) -> i32 {
+    fn select_ucb_arm(&self, group_id: usize, period: usize) -> usize {
         let arms = &self.arms[&group_id][&period];
 
         // Calculate total number of pulls across all arms
-        let total_pulls: usize = arms.values().map(|arm| arm.num_pulls).sum();
+        let total_pulls: usize = arms.values().map(|arm| arm.num_pulls + arm.num_pending).sum();
 
         // If no pulls yet, pick a random arm
         if total_pulls == 0 {
@@ -172,10 +595,367 @@ impl<'a> MAB<'a> {
                 .max_by(|(_, score1), (_, score2)| score1.partial_cmp(score2).unwrap())
                 .unwrap();
 
-            *best_arm_id as i32
+            *best_arm_id
         }
     }
     
+    // Draw a reward for every arm from its posterior and play the best draw
+    fn select_thompson_arm(&self, group_id: usize, period: usize) -> usize {
+        let mut rng = rand::thread_rng();
+        let arms = &self.arms[&group_id][&period];
+        let (best_arm_id, _) = arms
+            .iter()
+            .map(|(arm_id, arm)| {
+                let sample = match self.strategy {
+                    MABStrategy::ThompsonGaussian => {
+                        let (mean, std) = arm.gaussian_posterior(self.prior_std);
+                        rng.sample(Normal::new(mean, std).unwrap())
+                    }
+                    _ => {
+                        let (alpha, beta) = arm.beta_posterior();
+                        arm.price * rng.sample(Beta::new(alpha, beta).unwrap())
+                    }
+                };
+                (arm_id, sample)
+            })
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .unwrap();
+        *best_arm_id
+    }
+
+    // Beta posterior of the purchase probability at `arm_id` for a group and period,
+    // with three levels that each see an offer once. The cell pools the same price
+    // in the periods of the group around `period`, with a weight that decays with
+    // the distance in periods. Its prior, worth `prior_strength` offers, is the
+    // conversion of the price in the other periods of the group, whose own prior
+    // is the conversion of the price in the other groups.
+    pub fn pooled_posterior(&self, group_id: usize, period: usize, arm_id: usize) -> (f64, f64) {
+        let mut other_sales = 0.0;
+        let mut other_pulls = 0.0;
+        for (&(segment, price), &(sales, pulls)) in self.price_totals.iter() {
+            if segment != group_id && price == arm_id {
+                other_sales += sales as f64;
+                other_pulls += pulls as f64;
+            }
+        }
+        let other_groups_rate = (other_sales + 1.0) / (other_pulls + 2.0);
+
+        let (group_sales, group_pulls) = self.price_totals.get(&(group_id, arm_id)).copied().unwrap_or((0, 0));
+        let (mut outside_sales, mut outside_pulls) = (group_sales as f64, group_pulls as f64);
+        let mut sales = 0.0;
+        let mut pulls = 0.0;
+        let group_arms = &self.arms[&group_id];
+        let reach = (3.0 * self.pooling_bandwidth).ceil() as usize;
+        for other in period.saturating_sub(reach)..=(period + reach).min(group_arms.len() - 1) {
+            let weight = (-(other.abs_diff(period) as f64) / self.pooling_bandwidth.max(f64::EPSILON)).exp();
+            let arm = &group_arms[&other][&arm_id];
+            sales += weight * arm.num_sales as f64;
+            pulls += weight * arm.observed_pulls() as f64;
+            outside_sales -= arm.num_sales as f64;
+            outside_pulls -= arm.observed_pulls() as f64;
+        }
+        let group_rate =
+            (self.prior_strength * other_groups_rate + outside_sales) / (self.prior_strength + outside_pulls);
+        (
+            self.prior_strength * group_rate + sales,
+            self.prior_strength * (1.0 - group_rate) + pulls - sales,
+        )
+    }
+
+    fn select_hierarchical_arm(&self, group_id: usize, period: usize) -> usize {
+        let mut rng = rand::thread_rng();
+        let (best_arm_id, _) = self.arms[&group_id][&period]
+            .iter()
+            .map(|(arm_id, arm)| {
+                let (alpha, beta) = self.pooled_posterior(group_id, period, *arm_id);
+                (arm_id, arm.price * rng.sample(Beta::new(alpha, beta).unwrap()))
+            })
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .unwrap();
+        *best_arm_id
+    }
+
+    /// Hoeffding confidence bounds on the mean revenue of an arm, valid for all
+    /// arms and pull counts of a cell with probability `1 - confidence_delta`.
+    /// The rewards of an arm are either 0 or its price, which sets the range.
+    pub fn confidence_bounds(&self, arm: &Arm) -> (f64, f64) {
+        let range = arm.price.max(1.0);
+        if arm.observed_pulls() == 0 {
+            return (0.0, range);
+        }
+        let n = arm.observed_pulls() as f64;
+        let log_term = (4.0 * self.arms_per_group as f64 * n * n / self.confidence_delta).ln();
+        let radius = range * (log_term / (2.0 * n)).sqrt();
+        (arm.observed_mean() - radius, arm.observed_mean() + radius)
+    }
+
+    // Arms of a cell that are still in the running, as (arm_id, arm)
+    fn active_arms(&self, group_id: usize, period: usize) -> Vec<(usize, &Arm)> {
+        let mut active: Vec<(usize, &Arm)> = self.arms[&group_id][&period]
+            .iter()
+            .filter(|(_, arm)| !arm.eliminated)
+            .map(|(arm_id, arm)| (*arm_id, arm))
+            .collect();
+        active.sort_unstable_by_key(|(arm_id, _)| *arm_id);
+        active
+    }
+
+    // Active arm with the fewest offers, counting those still awaiting feedback
+    fn select_round_robin_arm(&self, group_id: usize, period: usize) -> usize {
+        self.active_arms(group_id, period)
+            .into_iter()
+            .min_by_key(|(_, arm)| arm.num_pulls + arm.num_pending)
+            .unwrap()
+            .0
+    }
+
+    fn select_lucb_arm(&self, group_id: usize, period: usize) -> usize {
+        let active = self.active_arms(group_id, period);
+        if active.len() == 1 {
+            return active[0].0;
+        }
+        if let Some((arm_id, _)) = active.iter().find(|(_, arm)| arm.num_pulls + arm.num_pending == 0) {
+            return *arm_id;
+        }
+        let (leader, challenger) = self.lucb_pair(&active);
+        // sample whichever of the two is known less precisely
+        let pulls = |arm: &Arm| arm.num_pulls + arm.num_pending;
+        if pulls(leader.1) <= pulls(challenger.1) {
+            leader.0
+        } else {
+            challenger.0
+        }
+    }
+
+    // Empirical best arm and the other arm with the highest upper bound
+    fn lucb_pair<'b>(&self, active: &[(usize, &'b Arm)]) -> ((usize, &'b Arm), (usize, &'b Arm)) {
+        let leader = *active
+            .iter()
+            .max_by(|(_, a), (_, b)| a.average_reward.partial_cmp(&b.average_reward).unwrap())
+            .unwrap();
+        let challenger = *active
+            .iter()
+            .filter(|(arm_id, _)| *arm_id != leader.0)
+            .max_by(|(_, a), (_, b)| {
+                self.confidence_bounds(a).1.partial_cmp(&self.confidence_bounds(b).1).unwrap()
+            })
+            .unwrap();
+        (leader, challenger)
+    }
+
+    // Drops the arms that the outcome just observed shows to be dominated
+    fn eliminate_arms(&mut self, group_id: usize, period: usize) {
+        let active = self.active_arms(group_id, period);
+        if active.len() <= 1 {
+            return;
+        }
+        let mut next_target = None;
+        let dominated: Vec<usize> = match self.strategy {
+            MABStrategy::SuccessiveElimination => {
+                let best_lower = active
+                    .iter()
+                    .map(|(_, arm)| self.confidence_bounds(arm).0)
+                    .fold(f64::NEG_INFINITY, f64::max);
+                active
+                    .iter()
+                    .filter(|(_, arm)| self.confidence_bounds(arm).1 < best_lower)
+                    .map(|(arm_id, _)| *arm_id)
+                    .collect()
+            }
+            MABStrategy::SuccessiveHalving => {
+                let rounds = (self.arms_per_group as f64).log2().ceil().max(1.0);
+                let per_arm = |n_arms: usize| ((self.halving_budget as f64 / (n_arms as f64 * rounds)) as usize).max(1);
+                let target = self
+                    .halving_targets
+                    .get(&(group_id, period))
+                    .copied()
+                    .unwrap_or_else(|| per_arm(active.len()));
+                if active.iter().any(|(_, arm)| arm.num_pulls < target) {
+                    return;
+                }
+                let mut ranked = active.clone();
+                ranked.sort_by(|(_, a), (_, b)| b.average_reward.partial_cmp(&a.average_reward).unwrap());
+                let keep = ranked.len().div_ceil(2);
+                next_target = Some(target + per_arm(keep));
+                ranked[keep..].iter().map(|(arm_id, _)| *arm_id).collect()
+            }
+            MABStrategy::LUCB => {
+                if active.iter().any(|(_, arm)| arm.num_pulls == 0) {
+                    return;
+                }
+                let (leader, challenger) = self.lucb_pair(&active);
+                if self.confidence_bounds(leader.1).0 > self.confidence_bounds(challenger.1).1 {
+                    // the leader is identified, every other arm is dominated
+                    active.iter().filter(|(arm_id, _)| *arm_id != leader.0).map(|(arm_id, _)| *arm_id).collect()
+                } else {
+                    Vec::new()
+                }
+            }
+            _ => Vec::new(),
+        };
+        if let Some(target) = next_target {
+            self.halving_targets.insert((group_id, period), target);
+        }
+        let arms = self.arms.get_mut(&group_id).unwrap().get_mut(&period).unwrap();
+        for arm_id in dominated {
+            arms.get_mut(&arm_id).unwrap().eliminated = true;
+        }
+    }
+
+    // UCB over the offers that are still in the sliding window
+    fn select_sliding_window_arm(&self, group_id: usize, period: usize) -> usize {
+        let recent = match self.recent.get(&(group_id, period)) {
+            Some(recent) if !recent.is_empty() => recent,
+            _ => return self.random_action(),
+        };
+        let mut stats: HashMap<usize, (f64, usize)> = HashMap::new();
+        for (arm_id, reward) in recent {
+            let entry = stats.entry(*arm_id).or_insert((0.0, 0));
+            entry.0 += reward;
+            entry.1 += 1;
+        }
+        let total = recent.len() as f64;
+        let (best_arm_id, _) = self.arms[&group_id][&period]
+            .keys()
+            .map(|arm_id| {
+                let score = match stats.get(arm_id) {
+                    Some((sum, n)) => sum / *n as f64 / self.reward_scale + (self.ucb_param * total.ln() / *n as f64).sqrt(),
+                    None => f64::INFINITY,
+                };
+                (arm_id, score)
+            })
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .unwrap();
+        *best_arm_id
+    }
+
+    // UCB on discounted averages and discounted pull counts
+    fn select_discounted_arm(&self, group_id: usize, period: usize) -> usize {
+        let arms = &self.arms[&group_id][&period];
+        let total: f64 = arms.values().map(|arm| arm.discounted_pulls).sum();
+        if total == 0.0 {
+            return self.random_action();
+        }
+        let (best_arm_id, _) = arms
+            .iter()
+            .map(|(arm_id, arm)| {
+                let score = if arm.discounted_pulls > 0.0 {
+                    arm.discounted_average() / self.reward_scale + (self.ucb_param * total.max(1.0).ln() / arm.discounted_pulls).sqrt()
+                } else {
+                    f64::INFINITY
+                };
+                (arm_id, score)
+            })
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .unwrap();
+        *best_arm_id
+    }
+
+    // EXP3 probability of playing `arm_id`: weights mixed with uniform exploration
+    fn exp3_probability(&self, group_id: usize, period: usize, arm_id: usize) -> f64 {
+        let arms = &self.arms[&group_id][&period];
+        let total: f64 = arms.values().map(|arm| arm.weight).sum();
+        (1.0 - self.exp3_gamma) * arms[&arm_id].weight / total + self.exp3_gamma / arms.len() as f64
+    }
+
+    fn select_exp3_arm(&self, group_id: usize, period: usize) -> usize {
+        let mut draw = rand::thread_rng().gen::<f64>();
+        let mut arm_ids: Vec<usize> = self.arms[&group_id][&period].keys().copied().collect();
+        arm_ids.sort_unstable();
+        for arm_id in arm_ids.iter() {
+            draw -= self.exp3_probability(group_id, period, *arm_id);
+            if draw <= 0.0 {
+                return *arm_id;
+            }
+        }
+        *arm_ids.last().unwrap()
+    }
+
+    // Importance-weighted EXP3(.S) update with the reward scaled to [0, 1], `probability`
+    // is the probability of playing the arm when it was chosen
+    fn update_exp3_weights(&mut self, group_id: usize, period: usize, arm_id: usize, reward: f64, probability: f64) {
+        let arms = self.arms.get_mut(&group_id).unwrap().get_mut(&period).unwrap();
+        let k = arms.len() as f64;
+        let estimate = (reward / self.prior_std).clamp(0.0, 1.0) / probability;
+        let arm = arms.get_mut(&arm_id).unwrap();
+        arm.weight *= (self.exp3_gamma * estimate / k).exp();
+
+        let share = if self.strategy == MABStrategy::EXP3S {
+            std::f64::consts::E * self.exp3s_alpha / k * arms.values().map(|arm| arm.weight).sum::<f64>()
+        } else {
+            0.0
+        };
+        let max_weight = arms.values().map(|arm| arm.weight + share).fold(0.0, f64::max);
+        for arm in arms.values_mut() {
+            arm.weight = (arm.weight + share) / max_weight;
+        }
+    }
+
+    // Forget everything learned for a group and period
+    fn restart(&mut self, group_id: usize, period: usize) {
+        for (arm_id, arm) in self.arms.get_mut(&group_id).unwrap().get_mut(&period).unwrap().iter_mut() {
+            let totals = self.price_totals.entry((group_id, *arm_id)).or_insert((0, 0));
+            totals.0 -= arm.num_sales;
+            totals.1 -= arm.observed_pulls();
+            *arm = Arm::new(arm.price);
+        }
+        self.best_rewards.get_mut(&group_id).unwrap().insert(period, 0.0);
+        self.recent.remove(&(group_id, period));
+        self.n_restarts += 1;
+    }
+
+    // Best arm under the current (windowed or discounted) estimate of each strategy
+    fn refresh_best_arm(&mut self, group_id: usize, period: usize) {
+        let mut estimates: HashMap<usize, f64> = HashMap::new();
+        match self.strategy {
+            MABStrategy::SlidingWindowUCB => {
+                let mut counts: HashMap<usize, usize> = HashMap::new();
+                for (arm_id, reward) in self.recent.get(&(group_id, period)).into_iter().flatten() {
+                    *estimates.entry(*arm_id).or_insert(0.0) += reward;
+                    *counts.entry(*arm_id).or_insert(0) += 1;
+                }
+                for (arm_id, n) in counts {
+                    *estimates.get_mut(&arm_id).unwrap() /= n as f64;
+                }
+            }
+            MABStrategy::DiscountedUCB => {
+                for (arm_id, arm) in self.arms[&group_id][&period].iter() {
+                    estimates.insert(*arm_id, arm.discounted_average());
+                }
+            }
+            MABStrategy::EXP3 | MABStrategy::EXP3S => {
+                for (arm_id, arm) in self.arms[&group_id][&period].iter() {
+                    estimates.insert(*arm_id, arm.weight);
+                }
+            }
+            MABStrategy::HierarchicalThompson => {
+                for (arm_id, arm) in self.arms[&group_id][&period].iter() {
+                    let (alpha, beta) = self.pooled_posterior(group_id, period, *arm_id);
+                    estimates.insert(*arm_id, arm.price * alpha / (alpha + beta) / self.reward_scale);
+                }
+            }
+            MABStrategy::SuccessiveElimination | MABStrategy::SuccessiveHalving | MABStrategy::LUCB => {
+                for (arm_id, arm) in self.active_arms(group_id, period) {
+                    if arm.num_pulls > 0 {
+                        estimates.insert(arm_id, arm.average_reward / self.reward_scale);
+                    }
+                }
+            }
+            _ => {
+                for (arm_id, arm) in self.arms[&group_id][&period].iter() {
+                    estimates.insert(*arm_id, self.arm_value(arm));
+                }
+            }
+        }
+        if let Some((arm_id, estimate)) = estimates
+            .into_iter()
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+        {
+            self.best_arms.get_mut(&group_id).unwrap().insert(period, arm_id);
+            self.best_rewards.get_mut(&group_id).unwrap().insert(period, estimate);
+        }
+    }
+
     // Calculate the current epsilon based on decay schedule
     fn get_current_epsilon(&self) -> f64 {
         if self.n_runs <= 1 {
@@ -194,22 +974,246 @@ impl<'a> MAB<'a> {
         }
     }
 
+    /// Writes the complete learned state to `path`: strategy, run counter, arm
+    /// layout, action space and the statistics of every arm. One record per line,
+    /// the first field names the kind of record.
+    pub fn save(&self, path: &str) {
+        let mut writer = csv::WriterBuilder::new().flexible(true).from_path(path).unwrap();
+        writer.write_record(["strategy", &self.strategy.to_string()]).unwrap();
+        writer.write_record(["run_id", &self.run_id.to_string()]).unwrap();
+        writer
+            .write_record([
+                "arm_key",
+                &self.arm_key.group.to_string(),
+                &self.arm_key.visit.to_string(),
+                &self.arm_key.period_bucket.map_or(String::new(), |bucket| bucket.to_string()),
+            ])
+            .unwrap();
+        let mut action_space = vec!["action_space".to_string()];
+        action_space.extend(self.action_space.iter().map(|price| price.to_string()));
+        writer.write_record(&action_space).unwrap();
+        writer.write_record(["n_restarts", &self.n_restarts.to_string()]).unwrap();
+
+        for (group_id, group_arms) in self.arms.iter() {
+            for (period, arms) in group_arms.iter() {
+                for (arm_id, arm) in arms.iter() {
+                    writer
+                        .write_record([
+                            "arm".to_string(),
+                            group_id.to_string(),
+                            period.to_string(),
+                            arm_id.to_string(),
+                            arm.price.to_string(),
+                            arm.average_reward.to_string(),
+                            arm.num_pulls.to_string(),
+                            arm.num_sales.to_string(),
+                            arm.m2.to_string(),
+                            arm.discounted_pulls.to_string(),
+                            arm.discounted_reward.to_string(),
+                            arm.weight.to_string(),
+                            arm.ph_cumulative.to_string(),
+                            arm.ph_min.to_string(),
+                            arm.eliminated.to_string(),
+                            arm.prior_pulls.to_string(),
+                        ])
+                        .unwrap();
+                }
+                writer
+                    .write_record([
+                        "best".to_string(),
+                        group_id.to_string(),
+                        period.to_string(),
+                        self.best_arms[group_id][period].to_string(),
+                        self.best_rewards[group_id][period].to_string(),
+                    ])
+                    .unwrap();
+            }
+        }
+        for ((group_id, period), recent) in self.recent.iter() {
+            for (arm_id, reward) in recent {
+                writer
+                    .write_record([
+                        "recent".to_string(),
+                        group_id.to_string(),
+                        period.to_string(),
+                        arm_id.to_string(),
+                        reward.to_string(),
+                    ])
+                    .unwrap();
+            }
+        }
+        for ((group_id, period), target) in self.halving_targets.iter() {
+            writer
+                .write_record([
+                    "halving".to_string(),
+                    group_id.to_string(),
+                    period.to_string(),
+                    target.to_string(),
+                ])
+                .unwrap();
+        }
+        writer.flush().unwrap();
+    }
+
+    /// Resumes a bandit written by `save`. The strategy, arm layout, action space
+    /// and run counter come from the file; the remaining parameters (exploration
+    /// rates, safety limits, ...) from `algorithm_settings`.
+    pub fn load(
+        path: &str,
+        settings: &ProblemSettings,
+        algorithm_settings: &MABSettings,
+        writer: &'a mut csv::Writer<File>,
+        config_id: usize,
+    ) -> Self {
+        let mut mab = Self::empty(settings, algorithm_settings, writer, 0, config_id);
+
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .has_headers(false)
+            .from_path(path)
+            .unwrap();
+        for record in reader.records() {
+            let record = record.unwrap();
+            let field = |i: usize| record.get(i).unwrap();
+            let usize_field = |i: usize| field(i).parse::<usize>().unwrap();
+            let f64_field = |i: usize| field(i).parse::<f64>().unwrap();
+            match field(0) {
+                "strategy" => mab.strategy = field(1).parse().unwrap(),
+                "run_id" => mab.run_id = usize_field(1),
+                "arm_key" => {
+                    mab.arm_key = ArmKey {
+                        group: field(1).parse().unwrap(),
+                        visit: field(2).parse().unwrap(),
+                        period_bucket: field(3).parse().ok(),
+                    };
+                    mab.n_visit_keys = if mab.arm_key.visit { settings.n_visits as usize } else { 1 };
+                }
+                "action_space" => {
+                    mab.action_space = record.iter().skip(1).map(|price| price.parse().unwrap()).collect();
+                    mab.arms_per_group = mab.action_space.len();
+                }
+                "n_restarts" => mab.n_restarts = usize_field(1),
+                "arm" => {
+                    let arm = Arm {
+                        price: field(4).parse().unwrap(),
+                        average_reward: f64_field(5),
+                        num_pulls: usize_field(6),
+                        num_sales: usize_field(7),
+                        m2: f64_field(8),
+                        num_pending: 0,
+                        eliminated: field(14).parse().unwrap(),
+                        discounted_pulls: f64_field(9),
+                        discounted_reward: f64_field(10),
+                        weight: f64_field(11),
+                        ph_cumulative: f64_field(12),
+                        ph_min: f64_field(13),
+                        prior_pulls: usize_field(15),
+                    };
+                    mab.arms
+                        .entry(usize_field(1))
+                        .or_default()
+                        .entry(usize_field(2))
+                        .or_default()
+                        .insert(usize_field(3), arm);
+                }
+                "best" => {
+                    mab.best_arms.entry(usize_field(1)).or_default().insert(usize_field(2), usize_field(3));
+                    mab.best_rewards.entry(usize_field(1)).or_default().insert(usize_field(2), f64_field(4));
+                }
+                "recent" => {
+                    mab.recent
+                        .entry((usize_field(1), usize_field(2)))
+                        .or_default()
+                        .push_back((usize_field(3), f64_field(4)));
+                }
+                "halving" => {
+                    mab.halving_targets.insert((usize_field(1), usize_field(2)), usize_field(3));
+                }
+                kind => panic!("unknown record {} in {}", kind, path),
+            }
+        }
+
+        mab.num_arms = mab.arms.values().flat_map(|group_arms| group_arms.values()).map(|arms| arms.len()).sum();
+        for (group_id, group_arms) in mab.arms.iter() {
+            for (arm_id, arm) in group_arms.values().flat_map(|arms| arms.iter()) {
+                let totals = mab.price_totals.entry((*group_id, *arm_id)).or_insert((0, 0));
+                totals.0 += arm.num_sales;
+                totals.1 += arm.observed_pulls();
+            }
+        }
+        mab
+    }
+
+    /// Warm-starts the bandit from another policy, e.g. the best individual of an
+    /// ES run. The policy is asked for its price at the first period of every
+    /// cell, so it reads its own prices the way it does during a run. The grid
+    /// price closest to that price becomes the best arm of the cell, and
+    /// `prior_pulls` pseudo-offers sold at that price enter its average reward
+    /// and pull count, so value-based strategies try it first until real
+    /// outcomes say otherwise. They are not sales: Beta posteriors, conversion
+    /// rates, the pooled counts and the confidence bounds only see real offers.
+    pub fn warm_start(&mut self, policy: &mut dyn Algorithm, prior_pulls: usize) {
+        let cells: Vec<(usize, usize)> = self
+            .arms
+            .iter()
+            .flat_map(|(group_id, group_arms)| group_arms.keys().map(move |period| (*group_id, *period)))
+            .collect();
+        for (segment, bucket) in cells {
+            let group_id = segment / self.n_visit_keys;
+            let visit = segment % self.n_visit_keys;
+            let period = bucket * self.arm_key.period_bucket.unwrap_or(0);
+            let price = policy.get_price(&PricingContext {
+                customer: 0,
+                group_id,
+                visit,
+                t: period as f64,
+                period,
+                history: CustomerObservations {
+                    visits: visit,
+                    ..Default::default()
+                },
+            });
+            let arm_id = self.nearest_arm(price);
+
+            let arm = self.arms.get_mut(&segment).unwrap().get_mut(&bucket).unwrap().get_mut(&arm_id).unwrap();
+            arm.add_prior(prior_pulls);
+            let reward = arm.average_reward;
+            self.best_arms.get_mut(&segment).unwrap().insert(bucket, arm_id);
+            self.best_rewards.get_mut(&segment).unwrap().insert(bucket, reward);
+        }
+    }
+
     pub fn log(&self, writer: &mut csv::Writer<File>) {
         for grou_arms in self.arms.iter() {
             let group_id = grou_arms.0;
             for period_arms in grou_arms.1.iter() {
                 let period_id = period_arms.0;
                 let best_arm = self.best_arms.get(group_id).unwrap().get(period_id).unwrap();
-                let num_pulls = self.arms.get(group_id).unwrap().get(period_id).unwrap().get(best_arm).unwrap().num_pulls;
+                let arm = self.arms.get(group_id).unwrap().get(period_id).unwrap().get(best_arm).unwrap();
+                let (alpha, beta) = arm.beta_posterior();
+                let (posterior_mean, posterior_std) = arm.gaussian_posterior(self.prior_std);
+                let (lower_bound, upper_bound) = self.confidence_bounds(arm);
+                let n_active = self.active_arms(*group_id, *period_id).len();
+                // dimensions the arms are not keyed by are left empty
+                let key = |keyed: bool, value: usize| if keyed { value.to_string() } else { String::new() };
                 writer
                     .write_record(&[
                         self.config_id.to_string(),
                         self.epsilon.to_string(),
                         self.strategy.to_string(),
-                        period_id.to_string(),
-                        group_id.to_string(),
-                        best_arm.to_string(),
-                        num_pulls.to_string()
+                        self.arm_key.period_bucket.map_or(String::new(), |bucket| (period_id * bucket).to_string()),
+                        key(self.arm_key.group, group_id / self.n_visit_keys),
+                        key(self.arm_key.visit, group_id % self.n_visit_keys),
+                        arm.price.to_string(),
+                        arm.num_pulls.to_string(),
+                        alpha.to_string(),
+                        beta.to_string(),
+                        posterior_mean.to_string(),
+                        posterior_std.to_string(),
+                        lower_bound.to_string(),
+                        upper_bound.to_string(),
+                        n_active.to_string(),
+                        (n_active == 1).to_string(),
                     ])
                     .unwrap();
             }
@@ -217,28 +1221,91 @@ impl<'a> MAB<'a> {
     }
 }
 
+/// What the platform knows about an offer when it sets the price.
+#[derive(Debug, Clone)]
+pub struct PricingContext {
+    pub customer: usize,
+    pub group_id: usize, // predicted group
+    pub visit: usize,    // offers the customer saw before this one, capped at n_visits - 1
+    pub t: f64,          // continuous arrival time
+    pub period: usize,
+    pub history: CustomerObservations, // the customer's offers and purchases before this one
+}
+
+/// What happened to an offer. `price` is always the price of the offer itself,
+/// `Converted` only occurs with an attribution window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Sold { price: f64 },
+    NoPurchase { price: f64 },
+    Quit { price: f64 },
+    Converted { price: f64, paid: f64 }, // declined, then bought at `paid` on a later visit in the window
+}
+
+impl Outcome {
+    pub fn price(&self) -> f64 {
+        match self {
+            Outcome::Sold { price }
+            | Outcome::NoPurchase { price }
+            | Outcome::Quit { price }
+            | Outcome::Converted { price, .. } => *price,
+        }
+    }
+
+    /// Revenue of the offer.
+    pub fn reward(&self) -> f64 {
+        match self {
+            Outcome::Sold { price } => *price,
+            Outcome::Converted { paid, .. } => *paid,
+            Outcome::NoPurchase { .. } | Outcome::Quit { .. } => 0.0,
+        }
+    }
+}
+
 pub trait Algorithm {
-    fn get_price(&mut self, group_id: usize, visit: usize, period: usize) -> i32;
-    fn update_average_reward(
-        &mut self,
-        group_id: usize,
-        visit: usize,
-        period: usize,
-        reward: f64,
-        price: i32,
-    );
+    /// Called once before the first event of a simulation episode.
+    fn on_episode_start(&mut self) {}
+    /// Called once after the last event of an episode with its result.
+    fn on_episode_end(&mut self, _result: &SimulationResult) {}
+    /// Called when the simulation clock enters a new (integer) period.
+    fn on_period_change(&mut self, _period: usize) {}
+    /// Called right before `get_price` with information the platform cannot
+    /// observe. Only clairvoyant benchmarks (see `oracle`) should use it.
+    fn observe_true_state(&mut self, _true_group: usize, _adjusted_wtp: f64) {}
+    /// A frozen learner neither explores nor learns: it offers its current best
+    /// price and ignores outcomes. Used for evaluation episodes.
+    fn set_frozen(&mut self, _frozen: bool) {}
+    fn get_price(&mut self, context: &PricingContext) -> f64;
+    /// Called right after `get_price` with the id that the outcome of this offer
+    /// will be reported under.
+    fn register_offer(&mut self, _offer_id: usize) {}
+    /// Outcome of a registered offer, delivered once the platform learns it, which
+    /// may be after later offers were made. `outcome` is `None` when the offer was
+    /// still unresolved at the end of the run (censored).
+    fn attribute_outcome(&mut self, _offer_id: usize, context: &PricingContext, outcome: Option<Outcome>) {
+        if let Some(outcome) = outcome {
+            self.update(context, outcome);
+        }
+    }
+    fn update(&mut self, context: &PricingContext, outcome: Outcome);
 }
 
 impl Algorithm for MAB<'_> {
-    fn get_price(&mut self, group_id: usize, visit: usize, period: usize) -> i32 {
-        let price = match self.strategy {
+    fn get_price(&mut self, context: &PricingContext) -> f64 {
+        let raw_period = context.period;
+        let (group_id, period) = self.cell(context.group_id, context.visit, context.period);
+        if self.frozen {
+            self.last_action = "frozen".to_string();
+            return self.action_space[self.best_arms[&group_id][&period]];
+        }
+        let arm_id = match self.strategy {
             MABStrategy::EpsilonGreedy => {
                 if rand::thread_rng().gen::<f64>() < self.epsilon {
                     self.last_action = "random".to_string();
                     self.random_action()
                 } else {
                     self.last_action = "best".to_string();
-                    self.best_arms[&group_id][&period] as i32
+                    self.best_arms[&group_id][&period]
                 }
             }
             MABStrategy::DecayingEpsilonGreedy => {
@@ -248,29 +1315,143 @@ impl Algorithm for MAB<'_> {
                     self.random_action()
                 } else {
                     self.last_action = "best".to_string();
-                    self.best_arms[&group_id][&period] as i32
+                    self.best_arms[&group_id][&period]
                 }
             }
             MABStrategy::UCB => {
                 self.last_action = "ucb".to_string();
                 self.select_ucb_arm(group_id, period)
             }
+            MABStrategy::ThompsonBeta | MABStrategy::ThompsonGaussian => {
+                self.last_action = "thompson".to_string();
+                self.select_thompson_arm(group_id, period)
+            }
+            MABStrategy::SlidingWindowUCB => {
+                self.last_action = "sw_ucb".to_string();
+                self.select_sliding_window_arm(group_id, period)
+            }
+            MABStrategy::DiscountedUCB => {
+                self.last_action = "d_ucb".to_string();
+                self.select_discounted_arm(group_id, period)
+            }
+            MABStrategy::EXP3 | MABStrategy::EXP3S => {
+                self.last_action = "exp3".to_string();
+                self.select_exp3_arm(group_id, period)
+            }
+            MABStrategy::ChangeDetectionUCB => {
+                self.last_action = "cd_ucb".to_string();
+                self.select_ucb_arm(group_id, period)
+            }
+            MABStrategy::HierarchicalThompson => {
+                self.last_action = "pooled_thompson".to_string();
+                self.select_hierarchical_arm(group_id, period)
+            }
+            MABStrategy::SuccessiveElimination | MABStrategy::SuccessiveHalving => {
+                self.last_action = "round_robin".to_string();
+                self.select_round_robin_arm(group_id, period)
+            }
+            MABStrategy::LUCB => {
+                self.last_action = "lucb".to_string();
+                self.select_lucb_arm(group_id, period)
+            }
+        };
+        let arm_id = self.make_safe(group_id, period, raw_period, arm_id);
+        let probability = match self.strategy {
+            MABStrategy::EXP3 | MABStrategy::EXP3S => self.exp3_probability(group_id, period, arm_id),
+            _ => 1.0,
         };
+        self.last_offer = Offer {
+            group_id,
+            period,
+            arm_id,
+            probability,
+            explored: arm_id != self.baseline_arm(group_id, period),
+        };
+
+        self.action_space[arm_id]
+    }
+
+    fn on_episode_start(&mut self) {
+        self.explorations.clear();
+        self.explored_revenue = 0.0;
+        self.explored_baseline = 0.0;
+        self.baseline_played = 0.0;
+    }
+
+    fn on_episode_end(&mut self, _result: &SimulationResult) {
+        // evaluation episodes of a frozen policy do not count as training runs
+        if !self.frozen {
+            self.increment_run();
+        }
+    }
+
+    fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+
+    fn register_offer(&mut self, offer_id: usize) {
+        if self.frozen {
+            return;
+        }
+        let offer = self.last_offer;
+        let arms = self.arms.get_mut(&offer.group_id).unwrap().get_mut(&offer.period).unwrap();
+        if let Some(arm) = arms.get_mut(&offer.arm_id) {
+            arm.num_pending += 1;
+            self.pending.insert(offer_id, offer);
+        }
+    }
+
+    fn attribute_outcome(&mut self, offer_id: usize, context: &PricingContext, outcome: Option<Outcome>) {
+        let Some(offer) = self.pending.remove(&offer_id) else {
+            if let Some(outcome) = outcome {
+                self.update(context, outcome);
+            }
+            return;
+        };
+        let arms = self.arms.get_mut(&offer.group_id).unwrap().get_mut(&offer.period).unwrap();
+        arms.get_mut(&offer.arm_id).unwrap().num_pending -= 1;
+        if let Some(outcome) = outcome {
+            if offer.explored && !self.frozen {
+                self.explored_revenue += outcome.reward();
+            }
+            // a late outcome is weighted by the probability the arm had when it was played
+            let (group_id, visit, period) = (context.group_id, context.visit, context.period);
+            self.record_reward(group_id, visit, period, outcome.reward(), offer.arm_id, Some(offer.probability));
+        }
+    }
 
-        return price;
+    fn update(&mut self, context: &PricingContext, outcome: Outcome) {
+        let arm_id = self.nearest_arm(outcome.price());
+        self.update_average_reward(context.group_id, context.visit, context.period, outcome.reward(), arm_id);
     }
+}
 
-    fn update_average_reward(
+impl MAB<'_> {
+    /// Tabular update of the arm `arm_id` of the cell of an offer.
+    pub fn update_average_reward(
         &mut self,
         group_id: usize,
         visit: usize,
         period: usize,
         reward: f64,
-        arm_id: i32,
+        arm_id: usize,
     ) {
-        let arms = self.arms.get_mut(&group_id).unwrap().get_mut(&period).unwrap();
-        let arm = arms.get_mut(&(arm_id as usize)).unwrap();
+        self.record_reward(group_id, visit, period, reward, arm_id, None);
+    }
 
+    // `probability` of playing the arm when it was chosen, None = its current probability
+    fn record_reward(
+        &mut self,
+        group_id: usize,
+        visit: usize,
+        period: usize,
+        reward: f64,
+        arm_id: usize,
+        probability: Option<f64>,
+    ) {
+        if self.frozen {
+            return;
+        }
         self.writer
             .write_record(&[
                 self.config_id.to_string(),
@@ -278,16 +1459,67 @@ impl Algorithm for MAB<'_> {
                 period.to_string(),
                 group_id.to_string(),
                 visit.to_string(),
-                arm_id.to_string(),
+                self.action_space[arm_id].to_string(),
                 reward.to_string(),
                 self.last_action.clone(),
             ])
             .unwrap();
 
-        arm.num_pulls += 1;
-        arm.average_reward =
-            (arm.average_reward * (arm.num_pulls as f64 - 1.0) + reward) / arm.num_pulls as f64;
-        if arm.average_reward > *self.best_rewards.get(&group_id).unwrap().get(&period).unwrap()
+        let (group_id, period) = self.cell(group_id, visit, period);
+        match self.strategy {
+            MABStrategy::SlidingWindowUCB => {
+                let recent = self.recent.entry((group_id, period)).or_default();
+                recent.push_back((arm_id, reward));
+                while recent.len() > self.window_size {
+                    recent.pop_front();
+                }
+            }
+            MABStrategy::DiscountedUCB => {
+                for arm in self.arms.get_mut(&group_id).unwrap().get_mut(&period).unwrap().values_mut() {
+                    arm.discounted_pulls *= self.discount;
+                    arm.discounted_reward *= self.discount;
+                }
+            }
+            MABStrategy::EXP3 | MABStrategy::EXP3S => {
+                let probability = probability.unwrap_or_else(|| self.exp3_probability(group_id, period, arm_id));
+                self.update_exp3_weights(group_id, period, arm_id, reward, probability);
+            }
+            MABStrategy::ChangeDetectionUCB => {
+                let (delta, threshold, scale) = (self.change_delta, self.change_threshold, self.prior_std);
+                let arm = self.arms.get_mut(&group_id).unwrap().get_mut(&period).unwrap().get_mut(&arm_id).unwrap();
+                if arm.detect_change(reward, scale, delta, threshold) {
+                    self.restart(group_id, period);
+                }
+            }
+            _ => {}
+        }
+
+        let totals = self.price_totals.entry((group_id, arm_id)).or_insert((0, 0));
+        totals.1 += 1;
+        if reward > 0.0 {
+            totals.0 += 1;
+        }
+
+        let arm = self.arms.get_mut(&group_id).unwrap().get_mut(&period).unwrap().get_mut(&arm_id).unwrap();
+        arm.update(reward);
+        if matches!(
+            self.strategy,
+            MABStrategy::SuccessiveElimination | MABStrategy::SuccessiveHalving | MABStrategy::LUCB
+        ) {
+            self.eliminate_arms(group_id, period);
+            self.refresh_best_arm(group_id, period);
+        } else if self.conversion_aware
+            || matches!(
+                self.strategy,
+                MABStrategy::SlidingWindowUCB
+                    | MABStrategy::DiscountedUCB
+                    | MABStrategy::EXP3
+                    | MABStrategy::EXP3S
+                    | MABStrategy::HierarchicalThompson
+            )
+        {
+            self.refresh_best_arm(group_id, period);
+        } else if arm.average_reward > *self.best_rewards.get(&group_id).unwrap().get(&period).unwrap()
         {
             self.best_rewards
                 .get_mut(&group_id)
@@ -296,7 +1528,338 @@ impl Algorithm for MAB<'_> {
             self.best_arms
                 .get_mut(&group_id)
                 .unwrap()
-                .insert(period, arm_id as usize);
+                .insert(period, arm_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::simulation::tests::problem_settings;
+
+    fn mab_settings(arm_key: ArmKey) -> MABSettings {
+        MABSettings {
+            min_price: 0.0,
+            max_price: 700.0,
+            arms_per_group: 5,
+            epsilon: 0.05,
+            final_epsilon: 0.01,
+            n_runs: 100,
+            ucb_param: 2.0,
+            strategy: MABStrategy::SlidingWindowUCB,
+            arm_key,
+            safety: SafeExploration::default(),
+            conversion_aware: false,
+            normalize_rewards: false,
+            window_size: 50,
+            discount: 0.999,
+            exp3_gamma: 0.1,
+            exp3s_alpha: 1e-4,
+            change_delta: 0.05,
+            change_threshold: 50.0,
+            pooling_bandwidth: 2.0,
+            prior_strength: 2.0,
+            confidence_delta: 0.05,
+            halving_budget: 200,
+        }
+    }
+
+    fn context(group_id: usize, period: usize) -> PricingContext {
+        PricingContext {
+            customer: 0,
+            group_id,
+            visit: 0,
+            t: period as f64,
+            period,
+            history: CustomerObservations::default(),
+        }
+    }
+
+    #[test]
+    fn exp3_weights_late_outcomes_by_the_probability_at_selection() {
+        let settings = problem_settings();
+        let mut algorithm_settings = mab_settings(ArmKey::group_period());
+        algorithm_settings.strategy = MABStrategy::EXP3;
+        let log_path = temp_path("exp3_log");
+        let mut writer = csv::Writer::from_path(&log_path).unwrap();
+        let mut mab = MAB::new(&settings, &algorithm_settings, &mut writer, 0, 0);
+
+        // all weights are equal, so every arm is played with probability 1 / 5,
+        // a zero price would leave the weights unchanged
+        let price = loop {
+            let price = mab.get_price(&context(0, 0));
+            if price > 0.0 {
+                break price;
+            }
+        };
+        mab.register_offer(0);
+        let arm_id = mab.nearest_arm(price);
+
+        // other outcomes arrive before this one and make the arm far more likely
+        for (other_id, arm) in mab.arms.get_mut(&0).unwrap().get_mut(&0).unwrap().iter_mut() {
+            arm.weight = if *other_id == arm_id { 1.0 } else { 0.01 };
+        }
+        mab.attribute_outcome(0, &context(0, 0), Some(Outcome::Sold { price }));
+
+        let estimate = (price / 700.0) / 0.2;
+        let growth = (0.1 * estimate / 5.0).exp();
+        let arms = &mab.arms[&0][&0];
+        assert_eq!(arms[&arm_id].weight, 1.0);
+        for (other_id, arm) in arms.iter().filter(|(other_id, _)| **other_id != arm_id) {
+            assert!((arm.weight - 0.01 / growth).abs() < 1e-12, "arm {} has weight {}", other_id, arm.weight);
+        }
+        assert_eq!(arms[&arm_id].num_pending, 0);
+        std::fs::remove_file(log_path).unwrap();
+    }
+
+    #[test]
+    fn conservative_floor_blocks_exploration_until_the_baseline_earned_slack() {
+        let settings = problem_settings();
+        let mut algorithm_settings = mab_settings(ArmKey::group_period());
+        algorithm_settings.strategy = MABStrategy::EpsilonGreedy;
+        algorithm_settings.safety.conservative_alpha = Some(0.1);
+        let log_path = temp_path("conservative_log");
+        let mut writer = csv::Writer::from_path(&log_path).unwrap();
+        let mut mab = MAB::new(&settings, &algorithm_settings, &mut writer, 0, 0);
+
+        // the baseline is the best arm, with a well known mean of 300
+        let (baseline, explored) = (3, 1);
+        mab.best_arms.get_mut(&0).unwrap().insert(0, baseline);
+        let arm = mab.arms.get_mut(&0).unwrap().get_mut(&0).unwrap().get_mut(&baseline).unwrap();
+        arm.average_reward = 300.0;
+        arm.num_pulls = 100;
+
+        // an untried arm is worth 0 in the worst case, there is no slack yet
+        mab.on_episode_start();
+        assert_eq!(mab.make_safe(0, 0, 0, explored), baseline);
+
+        // every baseline offer earns alpha * 300 of slack, 9 of them cover 0.9 * 300
+        for _ in 0..8 {
+            assert_eq!(mab.make_safe(0, 0, 0, baseline), baseline);
         }
+        assert_eq!(mab.make_safe(0, 0, 0, explored), explored);
+
+        // the exploratory offer has not earned anything yet, so the slack is used up
+        assert_eq!(mab.make_safe(0, 0, 0, explored), baseline);
+
+        // the floor holds per episode
+        mab.on_episode_start();
+        assert_eq!(mab.make_safe(0, 0, 0, explored), baseline);
+        std::fs::remove_file(log_path).unwrap();
+    }
+
+    #[test]
+    fn pooled_posterior_counts_every_offer_once() {
+        let settings = problem_settings();
+        let mut algorithm_settings = mab_settings(ArmKey::group_period());
+        algorithm_settings.strategy = MABStrategy::HierarchicalThompson;
+        let log_path = temp_path("pooled_log");
+        let mut writer = csv::Writer::from_path(&log_path).unwrap();
+        let mut mab = MAB::new(&settings, &algorithm_settings, &mut writer, 0, 0);
+        let arm_id = 2;
+        let price = mab.action_space[arm_id];
+        let offer = |mab: &mut MAB, group_id: usize, period: usize, n_sales: usize, n_offers: usize| {
+            for i in 0..n_offers {
+                let reward = if i < n_sales { price } else { 0.0 };
+                mab.update_average_reward(group_id, 0, period, reward, arm_id);
+            }
+        };
+
+        // the cell alone: a uniform prior worth prior_strength = 2 offers
+        offer(&mut mab, 0, 0, 4, 10);
+        assert_eq!(mab.pooled_posterior(0, 0, arm_id), (1.0 + 4.0, 1.0 + 6.0));
+
+        // period 9 is outside the pooling window of period 0, so it only moves the prior
+        offer(&mut mab, 0, 9, 10, 10);
+        let group_rate = (2.0 * 0.5 + 10.0) / (2.0 + 10.0);
+        let (alpha, beta) = mab.pooled_posterior(0, 0, arm_id);
+        assert!((alpha - (2.0 * group_rate + 4.0)).abs() < 1e-12);
+        assert!((alpha + beta - 12.0).abs() < 1e-12);
+
+        // so does the other group, through the prior of the group
+        offer(&mut mab, 1, 0, 0, 40);
+        let other_groups_rate = 1.0 / 42.0;
+        let group_rate = (2.0 * other_groups_rate + 10.0) / (2.0 + 10.0);
+        let (alpha, beta) = mab.pooled_posterior(0, 0, arm_id);
+        assert!((alpha - (2.0 * group_rate + 4.0)).abs() < 1e-12);
+        assert!((alpha + beta - 12.0).abs() < 1e-12);
+        std::fs::remove_file(log_path).unwrap();
+    }
+
+    #[test]
+    fn prior_strength_must_be_positive() {
+        let mut algorithm_settings = mab_settings(ArmKey::group_period());
+        assert!(algorithm_settings.validate().is_ok());
+        algorithm_settings.prior_strength = 0.0;
+        assert!(algorithm_settings.validate().is_err());
+    }
+
+    // Prices one grid arm per cell, higher for later groups, visits and period buckets
+    struct CellPolicy {
+        prices: Vec<f64>,
+        asked: Vec<(usize, usize, usize)>, // (group_id, visit, period) of every get_price
+    }
+
+    impl Algorithm for CellPolicy {
+        fn get_price(&mut self, context: &PricingContext) -> f64 {
+            assert_eq!(context.history.visits, context.visit);
+            self.asked.push((context.group_id, context.visit, context.period));
+            self.prices[context.group_id + context.visit + context.period / 5]
+        }
+
+        fn update(&mut self, _context: &PricingContext, _outcome: Outcome) {}
+    }
+
+    #[test]
+    fn warm_start_picks_the_policy_price_of_every_cell() {
+        let settings = problem_settings();
+        let arm_key = ArmKey {
+            group: true,
+            visit: true,
+            period_bucket: Some(5),
+        };
+        let mut algorithm_settings = mab_settings(arm_key);
+        algorithm_settings.strategy = MABStrategy::ThompsonBeta;
+        let log_path = temp_path("warm_start_log");
+        let mut writer = csv::Writer::from_path(&log_path).unwrap();
+        let mut mab = MAB::new(&settings, &algorithm_settings, &mut writer, 0, 0);
+        let mut policy = CellPolicy {
+            prices: mab.action_space.clone(),
+            asked: Vec::new(),
+        };
+        mab.warm_start(&mut policy, 10);
+
+        // 2 groups, 3 visits and 2 buckets of 5 periods
+        assert_eq!(policy.asked.len(), 12);
+        for group_id in 0..2 {
+            for visit in 0..3 {
+                for bucket in 0..2 {
+                    let segment = group_id * 3 + visit;
+                    assert!(policy.asked.contains(&(group_id, visit, bucket * 5)));
+                    let arm_id = group_id + visit + bucket;
+                    assert_eq!(mab.best_arms[&segment][&bucket], arm_id);
+                    assert_eq!(mab.best_rewards[&segment][&bucket], mab.action_space[arm_id]);
+
+                    let arm = &mab.arms[&segment][&bucket][&arm_id];
+                    assert_eq!((arm.num_pulls, arm.prior_pulls, arm.observed_pulls()), (10, 10, 0));
+                    assert_eq!(arm.average_reward, mab.action_space[arm_id]);
+                    // pseudo-offers are not sales
+                    assert_eq!(arm.num_sales, 0);
+                    assert_eq!(arm.beta_posterior(), (1.0, 1.0));
+                    assert_eq!(mab.confidence_bounds(arm).0, 0.0);
+                }
+            }
+        }
+        assert!(mab.price_totals.is_empty());
+
+        // a real offer moves the average, the observed mean is that offer alone
+        let arm_id = mab.best_arms[&5][&1];
+        mab.update_average_reward(1, 2, 5, 0.0, arm_id);
+        let arm = &mab.arms[&5][&1][&arm_id];
+        assert_eq!((arm.num_pulls, arm.observed_pulls()), (11, 1));
+        assert!((arm.average_reward - 700.0 * 10.0 / 11.0).abs() < 1e-9);
+        assert!(arm.observed_mean().abs() < 1e-9);
+        assert_eq!(mab.price_totals[&(5, arm_id)], (0, 1));
+        std::fs::remove_file(log_path).unwrap();
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mab_{}_{}.csv", name, std::process::id()))
+    }
+
+    // Records of a saved bandit, sorted since the arms are written in hash map order
+    fn saved_records(path: &PathBuf) -> Vec<String> {
+        let mut records: Vec<String> = std::fs::read_to_string(path).unwrap().lines().map(String::from).collect();
+        records.sort();
+        records
+    }
+
+    fn assert_round_trip(name: &str, arm_key: ArmKey) {
+        let settings = problem_settings();
+        let algorithm_settings = mab_settings(arm_key);
+        let log_path = temp_path(&format!("{}_log", name));
+        let mut writer = csv::Writer::from_path(&log_path).unwrap();
+        let mut mab = MAB::new(&settings, &algorithm_settings, &mut writer, 7, 0);
+
+        // give a few arms of different cells some history
+        let cells: Vec<(usize, usize)> = mab
+            .arms
+            .iter()
+            .flat_map(|(segment, segment_arms)| segment_arms.keys().map(move |bucket| (*segment, *bucket)))
+            .collect();
+        for (i, (segment, bucket)) in cells.iter().enumerate().take(3) {
+            let arm = mab.arms.get_mut(segment).unwrap().get_mut(bucket).unwrap().get_mut(&(i % 5)).unwrap();
+            arm.average_reward = 123.25 + i as f64;
+            arm.num_pulls = 4 + i;
+            arm.num_sales = 2;
+            arm.m2 = 0.1 + 0.2;
+            arm.discounted_pulls = 3.5;
+            arm.discounted_reward = 97.125;
+            arm.weight = 0.75;
+            arm.ph_cumulative = -1.5;
+            arm.ph_min = -2.0;
+            arm.eliminated = i == 2;
+            arm.prior_pulls = i;
+            mab.best_arms.get_mut(segment).unwrap().insert(*bucket, i % 5);
+            mab.best_rewards.get_mut(segment).unwrap().insert(*bucket, 123.25 + i as f64);
+        }
+        let (segment, bucket) = cells[0];
+        mab.recent.insert((segment, bucket), VecDeque::from(vec![(0, 123.25), (3, 0.0)]));
+        mab.halving_targets.insert((segment, bucket), 12);
+        mab.n_restarts = 2;
+
+        let path = temp_path(name);
+        mab.save(path.to_str().unwrap());
+
+        let mut load_writer = csv::Writer::from_path(&log_path).unwrap();
+        let loaded = MAB::load(path.to_str().unwrap(), &settings, &algorithm_settings, &mut load_writer, 0);
+        assert_eq!(loaded.arm_key, arm_key);
+        assert_eq!(loaded.run_id, 7);
+        assert_eq!(loaded.n_restarts, 2);
+        assert_eq!(loaded.action_space, mab.action_space);
+        assert_eq!(loaded.num_arms(), mab.num_arms());
+        assert_eq!(loaded.n_visit_keys, mab.n_visit_keys);
+        assert_eq!(loaded.recent[&(segment, bucket)], mab.recent[&(segment, bucket)]);
+        assert_eq!(loaded.halving_targets, mab.halving_targets);
+        assert_eq!(loaded.price_totals[&(segment, 0)], (2, 4));
+
+        let reloaded_path = temp_path(&format!("{}_reloaded", name));
+        loaded.save(reloaded_path.to_str().unwrap());
+        assert_eq!(saved_records(&reloaded_path), saved_records(&path));
+
+        for path in [path, reloaded_path, log_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        assert_round_trip("group_period", ArmKey::group_period());
+    }
+
+    #[test]
+    fn save_and_load_round_trip_without_period_bucket() {
+        let arm_key = ArmKey {
+            group: true,
+            visit: true,
+            period_bucket: None,
+        };
+        assert_round_trip("no_bucket", arm_key);
+
+        // the missing bucket is saved as an empty field
+        let settings = problem_settings();
+        let log_path = temp_path("no_bucket_field_log");
+        let mut writer = csv::Writer::from_path(&log_path).unwrap();
+        let mab = MAB::new(&settings, &mab_settings(arm_key), &mut writer, 0, 0);
+        let path = temp_path("no_bucket_field");
+        mab.save(path.to_str().unwrap());
+        let records = std::fs::read_to_string(&path).unwrap();
+        assert!(records.lines().any(|line| line == "arm_key,true,true,"));
+        assert_eq!(mab.num_arms(), 2 * 3 * 5);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(log_path).unwrap();
     }
 }
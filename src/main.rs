@@ -1,19 +1,21 @@
-use std::fs;
+use std::sync::Arc;
 
-use personalized_pricing::custom::{self, simulate_custom};
-use personalized_pricing::evolution::Adaptation;
-use personalized_pricing::evolution::{evolve_pricing, ESSettings, Selection};
+use personalized_pricing::clustering::{ConfusionMatrix, Segmentation};
+use personalized_pricing::custom::simulate_custom;
+use personalized_pricing::event_sink::CsvSink;
 use personalized_pricing::logging::{
-    init_log, init_log_es, init_log_mab, init_log_pso, log_event_history, log_individual
+    init_log, init_log_kpis, init_log_mab, init_log_oracle, init_log_training, init_log_welfare,
+    log_kpis, log_learning_curve, log_oracle_gaps, log_welfare,
 };
-use personalized_pricing::mab::{MABSettings, MABStrategy, MAB};
-use personalized_pricing::particle_swarm::{optimize_pricing, PSOSettings};
-use personalized_pricing::random_search::random_search;
-use personalized_pricing::simulation::{simulate_revenue, ProblemSettings};
+use personalized_pricing::mab::{Algorithm, ArmKey, MABSettings, MABStrategy, SafeExploration, MAB};
+use personalized_pricing::oracle::OracleBenchmarks;
+use personalized_pricing::price_grid::PriceGrid;
+use personalized_pricing::simulation::{simulate_revenue_with_sink, ProblemSettings};
+use personalized_pricing::training::{train, TrainingSettings};
 
 fn main() {
     let group_sizes = vec![20, 10, 30];
-    let mut settings = ProblemSettings {
+    let settings = Arc::new(ProblemSettings {
         n_customers: group_sizes.iter().sum(),
         n_periods: 100,
         n_groups: 3,
@@ -26,54 +28,60 @@ fn main() {
         alpha: 0.88,
         lambda: 2.25,
         eta: 0.5,
-        clustering_accuracy: 1.0,
         k_neighbors: 2,
         p_intra: 0.2,
         p_inter: 0.1,
         max_price: 700.0,
         num_predicted_groups: 3,
         sigmoid_scale: 200.0,
-        wtp_adjustment_amplitude: 0.7
-    };
+        wtp_adjustment_amplitude: 0.7,
+        segmentation: Segmentation::ConfusionMatrix(ConfusionMatrix::from_accuracy(3, 3, 1.0)),
+        resegmentation: None,
+        observation_window: 100.0,
+        wtp_signal_noise: 0.3,
+        attribution_window: None,
+        price_grid: PriceGrid::cents(),
+    });
+    settings.validate().unwrap();
 
-    let mut es_default_settings = ESSettings {
-        num_generations: 24,
-        lambda: 40,
-        mu: 20,
-        p: 2,
-        selection: Selection::Plus,
-        mutation_strength: 50.0,
-        adaptation: Adaptation::None,
-        rechenberg_window: 10,
-        fn_evals: 3,
-        resample: false
-        // num_period_prices: 10,
-        // num_visits: 10,
-    };
-    let mut es_steady_state_settings = ESSettings {
-        num_generations: 1000,
-        lambda: 1,
-        mu: 1,
-        p: 1,
-        selection: Selection::Plus,
-        mutation_strength: 50.0,
-        rechenberg_window: 20,
-        adaptation: Adaptation::RechenbergRule,
-        fn_evals: 3,
-        resample: false
-        // num_period_prices: 10,
-        // num_visits: 10,
-    };
-    let mut pso_settings = PSOSettings {
-    num_iterations: 100,
-        swarm_size: 10,
-        inertia_weight_start: 0.7,
-        inertia_weight_end: 0.7,
-        cognitive_coefficient: 1.5,
-        social_coefficient: 1.5,
-        fn_evals: 2,
-    };
-    let mut mab_settings = MABSettings {
+    // let mut es_default_settings = ESSettings {
+    //     num_generations: 24,
+    //     lambda: 40,
+    //     mu: 20,
+    //     p: 2,
+    //     selection: Selection::Plus,
+    //     mutation_strength: 50.0,
+    //     adaptation: Adaptation::None,
+    //     rechenberg_window: 10,
+    //     fn_evals: 3,
+    //     resample: false
+    //     // num_period_prices: 10,
+    //     // num_visits: 10,
+    // };
+    // let mut es_steady_state_settings = ESSettings {
+    //     num_generations: 1000,
+    //     lambda: 1,
+    //     mu: 1,
+    //     p: 1,
+    //     selection: Selection::Plus,
+    //     mutation_strength: 50.0,
+    //     rechenberg_window: 20,
+    //     adaptation: Adaptation::RechenbergRule,
+    //     fn_evals: 3,
+    //     resample: false
+    //     // num_period_prices: 10,
+    //     // num_visits: 10,
+    // };
+    // let mut pso_settings = PSOSettings {
+    //     num_iterations: 100,
+    //     swarm_size: 10,
+    //     inertia_weight_start: 0.7,
+    //     inertia_weight_end: 0.7,
+    //     cognitive_coefficient: 1.5,
+    //     social_coefficient: 1.5,
+    //     fn_evals: 2,
+    // };
+    let mab_settings = MABSettings {
         min_price: 0.0,
         max_price: settings.max_price,
         arms_per_group: 30,
@@ -82,9 +90,23 @@ fn main() {
         n_runs: 1000,
         ucb_param: 2.0,
         strategy: MABStrategy::UCB, // Change to desired strategy
+        arm_key: ArmKey::group_period(),
+        safety: SafeExploration::default(),
+        conversion_aware: false, // needs a much smaller ucb_param (~0.05) when enabled
+        normalize_rewards: false,
+        window_size: 500,
+        discount: 0.999,
+        exp3_gamma: 0.1,
+        exp3s_alpha: 1e-4,
+        change_delta: 0.05,
+        change_threshold: 50.0,
+        pooling_bandwidth: 2.0,
+        prior_strength: 2.0,
+        confidence_delta: 0.05,
+        halving_budget: 200,
     };
 
-    let mut writer = init_log();
+    init_log();
 
 
     // fs::remove_file("./results/custom_log.csv").unwrap_or_else(|e| {
@@ -116,11 +138,9 @@ fn main() {
     //     );
     //     for _ in 0..1000 {
     //         let result = simulate_revenue(&mut mab, &settings);
-    //         mab.run_id += 1;
     //     }
     //     mab.log(&mut arms_writer);
-    //     let result = simulate_revenue(&mut mab, &settings);
-    //     log_event_history(eps, &result, &settings);
+    //     simulate_revenue_with_sink(&mut mab, &settings, &mut CsvSink::new(eps, &settings));
     // }
     // let mut mab = MAB::new(
     //     &settings,
@@ -131,14 +151,12 @@ fn main() {
     // );
     // for _ in 0..1000 {
     //     let result = simulate_revenue(&mut mab, &settings);
-    //     mab.run_id += 1;
     // }
     // mab.log(&mut arms_writer);
-    // let result = simulate_revenue(&mut mab, &settings);
-    // log_event_history(0, &result, &settings);
+    // simulate_revenue_with_sink(&mut mab, &settings, &mut CsvSink::new(0, &settings));
     // mab_settings.strategy = MABStrategy::EpsilonGreedy;
 
-    let result = simulate_custom(&settings, 0, &mut custom_writer);
+    simulate_custom(&settings, 0, &mut custom_writer);
 
     // let mut mab = MAB::new(
     //     &settings,
@@ -149,11 +167,9 @@ fn main() {
     // );
     // for _ in 0..1000 {
     //     let result = simulate_revenue(&mut mab, &settings);
-    //     mab.run_id += 1;
     // }
     // mab.log(&mut arms_writer);
-    // let result = simulate_revenue(&mut mab, &settings);
-    // log_event_history(0, &result, &settings);
+    // simulate_revenue_with_sink(&mut mab, &settings, &mut CsvSink::new(0, &settings));
 
 
     // settings.wtp_adjustment_amplitude = 0.5;
@@ -165,26 +181,103 @@ fn main() {
         0,
         1
     );
-    for _ in 0..1000 {
-        let result = simulate_revenue(&mut mab, &settings);
-        mab.run_id += 1;
+    let training_settings = TrainingSettings {
+        n_episodes: 1000,
+        eval_every: 50,
+        n_eval_episodes: 10,
+        eval_seed: 0,
+        patience: None,
+        min_improvement: 0.01,
+    };
+    let report = train(&mut mab, &settings, &training_settings);
+    let mut training_writer = init_log_training();
+    log_learning_curve(&mut training_writer, "mab", &report);
+    mab.log(&mut arms_writer);
+    mab.save("./results/mab_state.csv");
+    let mut sink = CsvSink::new(1, &settings);
+    let result = simulate_revenue_with_sink(&mut mab, &settings, &mut sink);
+    sink.flush().unwrap();
+    let mut kpi_writer = init_log_kpis();
+    log_kpis(&mut kpi_writer, 1, &result.kpis);
+    let mut welfare_writer = init_log_welfare();
+    log_welfare(&mut welfare_writer, 1, &result.welfare);
 
-        if (mab.run_id > 970) {
-            custom_writer.write_record(&[
-                "1",
-                "1",
-                &result.revenue.to_string(),
-            ]).unwrap();
-        }
+    let benchmarks = OracleBenchmarks::compute(&settings, 30, 20);
+    println!(
+        "Oracles: first degree {:.2}, per true group {:.2} {:?}, per predicted group {:.2} {:?}",
+        benchmarks.first_degree,
+        benchmarks.per_true_group,
+        benchmarks.true_group_prices,
+        benchmarks.per_predicted_group,
+        benchmarks.predicted_group_prices
+    );
+    let mut oracle_writer = init_log_oracle();
+    // the gap measures the learned policy, without exploration or learning on the benchmark seeds
+    mab.set_frozen(true);
+    let mab_revenue = benchmarks.evaluate(&mut mab, &settings);
+    mab.set_frozen(false);
+    log_oracle_gaps(&mut oracle_writer, "mab", mab_revenue, &benchmarks);
 
-    }
-    mab.log(&mut arms_writer);
-    let result = simulate_revenue(&mut mab, &settings);
-    log_event_history(1, &result, &settings);
+    // let contextual_settings = ContextualSettings {
+    //     min_price: 0.0,
+    //     max_price: settings.max_price,
+    //     n_arms: 30,
+    //     strategy: ContextualStrategy::LinUCB,
+    //     alpha: 0.5,
+    //     regularization: 1.0,
+    //     posterior_scale: 0.2,
+    // };
+    // let mut contextual = ContextualBandit::new(&settings, &contextual_settings);
+    // for _ in 0..1000 {
+    //     simulate_revenue(&mut contextual, &settings);
+    // }
+    // contextual.set_frozen(true);
+    // let contextual_revenue = benchmarks.evaluate(&mut contextual, &settings);
+    // log_oracle_gaps(&mut oracle_writer, "contextual", contextual_revenue, &benchmarks);
 
+    // let continuous_settings = ContinuousSettings {
+    //     min_price: 0.0,
+    //     max_price: settings.max_price,
+    //     strategy: ContinuousStrategy::Zooming,
+    //     zoom_scale: 0.15,
+    //     max_arms: 40,
+    //     gp_grid_size: 141,
+    //     gp_length_scale: 0.1,
+    //     gp_noise: 0.5,
+    //     gp_beta: 0.1,
+    // };
+    // let mut continuous = ContinuousBandit::new(&settings, &continuous_settings);
+    // for _ in 0..1000 {
+    //     simulate_revenue(&mut continuous, &settings);
+    // }
+    // continuous.set_frozen(true);
+    // let continuous_revenue = benchmarks.evaluate(&mut continuous, &settings);
+    // log_oracle_gaps(&mut oracle_writer, "continuous", continuous_revenue, &benchmarks);
     
 
 
+    // let rl_settings = RLSettings {
+    //     min_price: 0.0,
+    //     max_price: settings.max_price,
+    //     n_actions: 30,
+    //     strategy: RLStrategy::QLearning,
+    //     learning_rate: 0.1,
+    //     discount: 0.9,
+    //     epsilon: 0.2,
+    //     final_epsilon: 0.01,
+    //     n_runs: 1000,
+    //     initial_value: 0.0,
+    //     period_bucket: 1,
+    //     n_reference_bins: 3,
+    // };
+    // let mut agent = TabularAgent::new(&settings, &rl_settings);
+    // let report = train(&mut agent, &settings, &training_settings);
+    // log_learning_curve(&mut training_writer, "q_learning", &report);
+    // agent.log(&mut init_log_q_table());
+    // agent.set_frozen(true);
+    // let agent_revenue = benchmarks.evaluate(&mut agent, &settings);
+    // log_oracle_gaps(&mut oracle_writer, "q_learning", agent_revenue, &benchmarks);
+
     // let n_iterations = 1000;
     // let best_solution = random_search(&settings, n_iterations, );
 
@@ -198,7 +291,7 @@ fn main() {
     //         evolve_pricing(run_id, &settings, &es_default_settings, &mut es_writer);
     //     log_individual("initial", run_id, &initial_best);
     //     log_individual("best", run_id, &best);
-    //     log_event_history(run_id, &best.simulation_result, &settings);
+    //     simulate_revenue_with_sink(&mut best.clone(), &settings, &mut CsvSink::new(run_id, &settings));
     //     println!("Best solution: {:?}", best.fitness_score);
     //     run_id += 1;
     // }
@@ -208,7 +301,7 @@ fn main() {
     //         evolve_pricing(run_id, &settings, &es_default_settings, &mut es_writer);
     //     log_individual("initial", run_id, &initial_best);
     //     log_individual("best", run_id, &best);
-    //     log_event_history(run_id, &best.simulation_result, &settings);
+    //     simulate_revenue_with_sink(&mut best.clone(), &settings, &mut CsvSink::new(run_id, &settings));
     //     println!("Best solution: {:?}", best.fitness_score);
     //     run_id += 1;
     // }
@@ -222,7 +315,7 @@ fn main() {
     //     let best_solution =
     //         evolve_pricing(run_id, &settings, &es_steady_state_settings, &mut es_writer);
     //     log_individual(run_id, &best_solution);
-    //     log_event_history(run_id, &best_solution.simulation_result, &settings);
+    //     simulate_revenue_with_sink(&mut best_solution.clone(), &settings, &mut CsvSink::new(run_id, &settings));
     //     run_id += 1;
     // }
     
@@ -253,7 +346,7 @@ fn main() {
     //     let best_solution =
     //         evolve_pricing(run_id, &settings, &es_steady_state_settings, &mut writer);
     //     log_individual(&best_solution);
-    //     log_event_history(run_id, &best_solution, &settings);
+    //     simulate_revenue_with_sink(&mut best_solution.clone(), &settings, &mut CsvSink::new(run_id, &settings));
     //     run_id += 1;
     // }
     // es_steady_state_settings.adaptation = Adaptation::None;
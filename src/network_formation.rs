@@ -1,8 +1,7 @@
 use crate::simulation::ProblemSettings;
 use rand::Rng;
 
-pub fn create_network(settings: &ProblemSettings) -> Vec<Vec<i32>> {
-    let mut rng = rand::thread_rng();
+pub fn create_network(settings: &ProblemSettings, rng: &mut impl Rng) -> Vec<Vec<i32>> {
     let mut network = vec![vec![]; settings.n_customers as usize];
 
     // Calculate starting index for each group
@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rand::Rng;
+
+use crate::event_sink::NoopSink;
+use crate::mab::{Algorithm, Outcome, PricingContext};
+use crate::simulation::{purchase_probability, simulate_revenue_seeded, ProblemSettings, QUIT_THRESHOLD};
+
+/// Perfect first-degree price discrimination: knows every customer's current
+/// (seasonally adjusted) wtp and offers the price that maximises the expected
+/// revenue of that offer.
+pub struct FirstDegreeOracle {
+    sigmoid_scale: f64,
+    adjusted_wtp: f64,
+}
+
+impl FirstDegreeOracle {
+    pub fn new(settings: &ProblemSettings) -> Self {
+        Self {
+            sigmoid_scale: settings.sigmoid_scale,
+            adjusted_wtp: 0.0,
+        }
+    }
+
+    /// Golden-section search of price * P(buy) on [0, QUIT_THRESHOLD * wtp],
+    /// the expected revenue is unimodal in the price.
+    pub fn optimal_price(wtp: f64, sigmoid_scale: f64) -> f64 {
+        if wtp <= 0.0 {
+            return 0.0;
+        }
+        let expected_revenue = |price: f64| price * purchase_probability(price, wtp, sigmoid_scale);
+        let ratio = (5.0_f64.sqrt() - 1.0) / 2.0;
+        let (mut low, mut high) = (0.0, wtp * QUIT_THRESHOLD);
+        while high - low > 1e-3 {
+            let left = high - ratio * (high - low);
+            let right = low + ratio * (high - low);
+            if expected_revenue(left) < expected_revenue(right) {
+                low = left;
+            } else {
+                high = right;
+            }
+        }
+        (low + high) / 2.0
+    }
+}
+
+impl Algorithm for FirstDegreeOracle {
+    fn observe_true_state(&mut self, _true_group: usize, adjusted_wtp: f64) {
+        self.adjusted_wtp = adjusted_wtp;
+    }
+
+    fn get_price(&mut self, _context: &PricingContext) -> f64 {
+        Self::optimal_price(self.adjusted_wtp, self.sigmoid_scale)
+    }
+
+    fn update(&mut self, _context: &PricingContext, _outcome: Outcome) {}
+}
+
+/// Which group a static price is keyed by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GroupKey {
+    True,
+    Predicted,
+}
+
+/// One price per group for the whole horizon.
+#[derive(Clone, Debug)]
+pub struct StaticPriceOracle {
+    pub prices: Vec<f64>,
+    pub key: GroupKey,
+    true_group: usize,
+}
+
+impl StaticPriceOracle {
+    pub fn new(prices: Vec<f64>, key: GroupKey) -> Self {
+        Self {
+            prices,
+            key,
+            true_group: 0,
+        }
+    }
+}
+
+impl Algorithm for StaticPriceOracle {
+    fn observe_true_state(&mut self, true_group: usize, _adjusted_wtp: f64) {
+        self.true_group = true_group;
+    }
+
+    fn get_price(&mut self, context: &PricingContext) -> f64 {
+        match self.key {
+            GroupKey::True => self.prices[self.true_group],
+            GroupKey::Predicted => self.prices[context.group_id],
+        }
+    }
+
+    fn update(&mut self, _context: &PricingContext, _outcome: Outcome) {}
+}
+
+/// Average revenue of `algorithm` over the given seeds.
+pub fn mean_revenue(algorithm: &mut dyn Algorithm, settings: &Arc<ProblemSettings>, seeds: &[u64]) -> f64 {
+    let total: f64 = seeds
+        .iter()
+        .map(|&seed| simulate_revenue_seeded(algorithm, settings, &mut NoopSink, seed).revenue)
+        .sum();
+    total / seeds.len() as f64
+}
+
+/// Search of one static price per group among the prices of `grid`, all
+/// candidates are evaluated on the same seeds. It starts from the best single
+/// price for all groups, then alternates sweeps over the full grid one group at
+/// a time with a scan of the product neighbourhood of the best prices (every
+/// group one grid step down, kept or up). The groups interact through word of
+/// mouth, the neighbourhood catches moves that only pay off for several groups
+/// together. Stops when neither improves on the best prices.
+pub fn best_static_prices(
+    settings: &Arc<ProblemSettings>,
+    key: GroupKey,
+    grid: &[f64],
+    seeds: &[u64],
+) -> (Vec<f64>, f64) {
+    let n_groups = match key {
+        GroupKey::True => settings.n_groups,
+        GroupKey::Predicted => settings.num_predicted_groups,
+    } as usize;
+    let mut oracle = StaticPriceOracle::new(vec![grid[0]; n_groups], key);
+    // the runs are seeded, so every candidate is simulated once
+    let mut revenues: HashMap<Vec<usize>, f64> = HashMap::new();
+    let mut revenue_of = |indices: &[usize]| {
+        *revenues.entry(indices.to_vec()).or_insert_with(|| {
+            for (price, index) in oracle.prices.iter_mut().zip(indices) {
+                *price = grid[*index];
+            }
+            mean_revenue(&mut oracle, settings, seeds)
+        })
+    };
+
+    let mut best = vec![0; n_groups];
+    let mut best_revenue = f64::NEG_INFINITY;
+    for index in 0..grid.len() {
+        let candidate = vec![index; n_groups];
+        let revenue = revenue_of(&candidate);
+        if revenue > best_revenue {
+            best = candidate;
+            best_revenue = revenue;
+        }
+    }
+
+    loop {
+        let start = best.clone();
+        for group in 0..n_groups {
+            for index in 0..grid.len() {
+                let mut candidate = best.clone();
+                candidate[group] = index;
+                let revenue = revenue_of(&candidate);
+                if revenue > best_revenue {
+                    best = candidate;
+                    best_revenue = revenue;
+                }
+            }
+        }
+
+        // odometer over the offsets -1, 0, +1 of all groups
+        let center = best.clone();
+        let mut offsets = vec![0; n_groups];
+        loop {
+            let candidate: Option<Vec<usize>> = center
+                .iter()
+                .zip(&offsets)
+                .map(|(index, offset)| (index + offset).checked_sub(1).filter(|index| *index < grid.len()))
+                .collect();
+            if let Some(candidate) = candidate {
+                let revenue = revenue_of(&candidate);
+                if revenue > best_revenue {
+                    best = candidate;
+                    best_revenue = revenue;
+                }
+            }
+
+            let Some(group) = offsets.iter().position(|offset| *offset < 2) else {
+                break;
+            };
+            offsets[group] += 1;
+            for offset in offsets[..group].iter_mut() {
+                *offset = 0;
+            }
+        }
+
+        if best == start {
+            break;
+        }
+    }
+    (best.iter().map(|index| grid[*index]).collect(), best_revenue)
+}
+
+/// Revenue gap of a policy to each oracle, in absolute terms and relative to
+/// the oracle revenue.
+#[derive(Clone, Debug, Default)]
+pub struct OracleGaps {
+    pub first_degree: f64,
+    pub per_true_group: f64,
+    pub per_predicted_group: f64,
+    pub first_degree_pct: f64,
+    pub per_true_group_pct: f64,
+    pub per_predicted_group_pct: f64,
+}
+
+/// Clairvoyant benchmarks evaluated on a fixed set of seeds, so any policy run
+/// on `seeds` can be compared against them with common random numbers.
+#[derive(Clone, Debug)]
+pub struct OracleBenchmarks {
+    pub seeds: Vec<u64>,
+    pub first_degree: f64,
+    pub per_true_group: f64,
+    pub per_predicted_group: f64,
+    pub true_group_prices: Vec<f64>,
+    pub predicted_group_prices: Vec<f64>,
+}
+
+impl OracleBenchmarks {
+    pub fn compute(settings: &Arc<ProblemSettings>, grid_size: usize, n_seeds: usize) -> Self {
+        let mut rng = rand::thread_rng();
+        let seeds: Vec<u64> = (0..n_seeds).map(|_| rng.gen()).collect();
+        // static prices are searched among the prices customers can be shown
+        let grid = settings.price_grid.prices(0.0, settings.max_price, grid_size);
+
+        let first_degree = mean_revenue(&mut FirstDegreeOracle::new(settings), settings, &seeds);
+        let (true_group_prices, per_true_group) = best_static_prices(settings, GroupKey::True, &grid, &seeds);
+        let (predicted_group_prices, per_predicted_group) =
+            best_static_prices(settings, GroupKey::Predicted, &grid, &seeds);
+
+        Self {
+            seeds,
+            first_degree,
+            per_true_group,
+            per_predicted_group,
+            true_group_prices,
+            predicted_group_prices,
+        }
+    }
+
+    /// Mean revenue of `algorithm` on the benchmark seeds.
+    pub fn evaluate(&self, algorithm: &mut dyn Algorithm, settings: &Arc<ProblemSettings>) -> f64 {
+        mean_revenue(algorithm, settings, &self.seeds)
+    }
+
+    pub fn gaps(&self, revenue: f64) -> OracleGaps {
+        let relative = |oracle: f64| if oracle > 0.0 { (oracle - revenue) / oracle } else { 0.0 };
+        OracleGaps {
+            first_degree: self.first_degree - revenue,
+            per_true_group: self.per_true_group - revenue,
+            per_predicted_group: self.per_predicted_group - revenue,
+            first_degree_pct: relative(self.first_degree),
+            per_true_group_pct: relative(self.per_true_group),
+            per_predicted_group_pct: relative(self.per_predicted_group),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::tests::problem_settings;
+
+    #[test]
+    fn optimal_price_maximises_expected_revenue() {
+        let sigmoid_scale = 200.0;
+        for wtp in [1.0, 125.0, 480.0] {
+            let expected_revenue = |price: f64| price * purchase_probability(price, wtp, sigmoid_scale);
+            let optimal = FirstDegreeOracle::optimal_price(wtp, sigmoid_scale);
+            let best_on_scan = (0..=15_000)
+                .map(|i| expected_revenue(i as f64 * 1e-4 * wtp))
+                .fold(f64::NEG_INFINITY, f64::max);
+            assert!(optimal > 0.0 && optimal < wtp);
+            assert!(expected_revenue(optimal) >= best_on_scan - 1e-6 * wtp);
+        }
+        assert_eq!(FirstDegreeOracle::optimal_price(0.0, sigmoid_scale), 0.0);
+    }
+
+    #[test]
+    fn per_group_prices_beat_a_single_price() {
+        let settings = Arc::new(problem_settings());
+        let seeds = [1, 2, 3];
+        let grid = settings.price_grid.prices(0.0, settings.max_price, 8);
+        let (prices, revenue) = best_static_prices(&settings, GroupKey::True, &grid, &seeds);
+        assert_eq!(prices.len(), 2);
+        assert!(prices.iter().all(|price| grid.contains(price)));
+
+        let mut flat = StaticPriceOracle::new(vec![0.0; 2], GroupKey::True);
+        for price in &grid {
+            flat.prices = vec![*price; 2];
+            assert!(revenue >= mean_revenue(&mut flat, &settings, &seeds));
+        }
+        // the reported revenue is the one of the returned prices
+        let mut oracle = StaticPriceOracle::new(prices, GroupKey::True);
+        assert_eq!(revenue, mean_revenue(&mut oracle, &settings, &seeds));
+    }
+}
@@ -1,6 +1,7 @@
 use crate::evolution::{Individual, PriceMatrix};
-use crate::simulation::{simulate_revenue, ProblemSettings, SimulationEvent, SimulationResult};
+use crate::simulation::{simulate_revenue, ProblemSettings, SimulationResult};
 use rand::Rng;
+use std::sync::Arc;
 use std::{collections::HashMap, fs::File};
 pub struct PSOSettings {
     pub num_iterations: i32,
@@ -11,26 +12,26 @@ pub struct PSOSettings {
     pub social_coefficient: f64,    // c2
     pub fn_evals: i32,
 }
-use crate::mab::Algorithm;
+use crate::mab::{Algorithm, Outcome, PricingContext};
 
 #[derive(Clone, Debug)]
-struct Particle<'a> {
+struct Particle {
     position: PriceMatrix, // Same structure as Individual's prices
     velocity: PriceMatrix,
     best_position: PriceMatrix,
     current_fitness: f64,
     best_fitness: f64,
     particle_id: i32,
-    simulation_result: SimulationResult<'a>,
+    simulation_result: SimulationResult,
 }
 
-impl<'a> Particle<'a> {
+impl Particle {
     fn new(
         particle_id: i32,
         n_visits: usize,
         n_periods: usize,
         n_groups: usize,
-        settings: &'a ProblemSettings,
+        settings: &Arc<ProblemSettings>,
     ) -> Self {
         let mut rng = rand::thread_rng();
         let mut position = HashMap::new();
@@ -54,22 +55,17 @@ impl<'a> Particle<'a> {
             velocity.insert(g, group_map_vel);
         }
 
+        let mut position = PriceMatrix(position);
+        position.snap(&settings.price_grid, settings.max_price);
+
         let mut particle = Self {
-            position: PriceMatrix(position.clone()),
-            velocity: PriceMatrix(velocity.clone()),
-            best_position: PriceMatrix(position.clone()),
+            position: position.clone(),
+            velocity: PriceMatrix(velocity),
+            best_position: position,
             current_fitness: 0.0,
             best_fitness: 0.0,
             particle_id,
-            simulation_result: SimulationResult {
-            event_history: vec![],
-            avg_regret: 0.0,
-            regret: 0.0,
-            customers: vec![],
-            avg_time_sold_at: 0.0,
-            n_sold: 0.0,
-            revenue: 0.0,
-        }
+            simulation_result: SimulationResult::default(),
         };
 
         // Evaluate initial position
@@ -81,7 +77,7 @@ impl<'a> Particle<'a> {
         particle
     }
 
-    fn to_individual(&self, result: SimulationResult<'a>) -> Individual<'a> {
+    fn to_individual(&self, result: SimulationResult) -> Individual {
         Individual {
             prices: self.position.clone(),
             fitness_score: self.current_fitness,
@@ -115,7 +111,7 @@ impl<'a> Particle<'a> {
         }
     }
 
-    fn update_position(&mut self) {
+    fn update_position(&mut self, settings: &ProblemSettings) {
         for (g, group_map) in self.position.0.iter_mut() {
             for (w, prices) in group_map.iter_mut() {
                 for t in 0..prices.len() {
@@ -125,14 +121,15 @@ impl<'a> Particle<'a> {
                 }
             }
         }
+        // the position lives on the displayed price grid, velocities may accumulate below it
+        self.position.snap(&settings.price_grid, settings.max_price);
     }
 }
 
-fn simulate_and_average<'a>(algorithm: &mut dyn Algorithm, settings: &'a ProblemSettings, n_evals:i32) -> (f64, SimulationResult<'a>) {
+fn simulate_and_average(algorithm: &mut dyn Algorithm, settings: &Arc<ProblemSettings>, _n_evals: i32) -> (f64, SimulationResult) {
     let mut total_revenue = 0.0;
-    let mut rng = rand::thread_rng();
     let n_runs = 10;
-    let mut best_simulation_result: Option<SimulationResult<'a>> = None;
+    let mut best_simulation_result: Option<SimulationResult> = None;
 
     for _ in 0..n_runs {
         let result = simulate_revenue(algorithm, settings);
@@ -143,34 +140,26 @@ fn simulate_and_average<'a>(algorithm: &mut dyn Algorithm, settings: &'a Problem
         }
     }
 
-    return (total_revenue / n_runs as f64, best_simulation_result.unwrap());
+    (total_revenue / n_runs as f64, best_simulation_result.unwrap())
 }
 
-impl<'a> Algorithm for Particle<'a> {
-    fn get_price(&mut self, group_id: usize, visit: usize, period: usize) -> i32 {
-        let converted_period = period % 10;
-        self.position.get_price(group_id, 0, converted_period) as i32
+impl Algorithm for Particle {
+    fn get_price(&mut self, context: &PricingContext) -> f64 {
+        let converted_period = context.period % 10;
+        self.position.get_price(context.group_id, 0, converted_period)
     }
 
-    fn update_average_reward(
-        &mut self,
-        _group_id: usize,
-        _visit: usize,
-        _period: usize,
-        reward: f64,
-        _price: i32,
-    ) {
-        self.current_fitness = reward;
+    fn update(&mut self, _context: &PricingContext, outcome: Outcome) {
+        self.current_fitness = outcome.reward();
     }
 }
 
 fn log_iteration(
     run_id: i32,
     writer: &mut csv::Writer<File>,
-    particles: &Vec<Particle>,
+    particles: &[Particle],
     iteration: i32,
     settings: &PSOSettings,
-    current_inertia: f64,
 ) {
     for particle in particles {
         // Calculate velocity norm
@@ -202,12 +191,12 @@ fn log_iteration(
     }
 }
 
-pub fn optimize_pricing<'a>(
+pub fn optimize_pricing(
     run_id: i32,
-    settings: &'a ProblemSettings,
+    settings: &Arc<ProblemSettings>,
     pso_settings: &PSOSettings,
-    mut writer: &mut csv::Writer<File>,
-) -> Individual<'a> {
+    writer: &mut csv::Writer<File>,
+) -> Individual {
     let mut particles: Vec<Particle> = Vec::new();
     let mut global_best_position = None;
     let mut global_best_fitness = f64::NEG_INFINITY;
@@ -220,7 +209,7 @@ pub fn optimize_pricing<'a>(
             i,
             settings.n_visits as usize,
             settings.n_periods as usize,
-            settings.n_groups as usize,
+            settings.num_predicted_groups as usize,
             settings,
         ));
 
@@ -244,7 +233,7 @@ pub fn optimize_pricing<'a>(
         for particle in particles.iter_mut() {
             // Update velocity and position with current inertia weight
             particle.update_velocity(global_best_position.as_ref().unwrap(), pso_settings, current_inertia);
-            particle.update_position();
+            particle.update_position(settings);
 
             // Evaluate new position
             let result = simulate_and_average(particle, settings, pso_settings.fn_evals);
@@ -267,7 +256,7 @@ pub fn optimize_pricing<'a>(
         num_evals += particles.len() as i32;
 
         // Pass the current inertia weight to the log function
-        log_iteration(run_id, &mut writer, &particles, num_evals, pso_settings, current_inertia);
+        log_iteration(run_id, writer, &particles, num_evals, pso_settings);
         println!(
             "Iteration {}: Best revenue = {}, Inertia = {}",
             num_evals, global_best_fitness, current_inertia
@@ -0,0 +1,139 @@
+/// Rule that turns the price set by a policy into the price shown to customers:
+/// the closest price of the form `k * step + ending` for a whole number `k`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PriceGrid {
+    pub step: f64,   // minimum increment between displayed prices, 0 = any price
+    pub ending: f64, // offset of every displayed price within its step, e.g. 0.99
+}
+
+impl PriceGrid {
+    /// Prices are shown as set.
+    pub fn exact() -> Self {
+        Self { step: 0.0, ending: 0.0 }
+    }
+
+    /// Prices rounded to the cent.
+    pub fn cents() -> Self {
+        Self { step: 0.01, ending: 0.0 }
+    }
+
+    /// Charm prices with the given ending in every whole unit, e.g. 4.99, 5.99, ...
+    pub fn charm(ending: f64) -> Self {
+        Self { step: 1.0, ending }
+    }
+
+    /// Multiples of a minimum increment, e.g. 5, 10, 15, ...
+    pub fn increments(step: f64) -> Self {
+        Self { step, ending: 0.0 }
+    }
+
+    /// Closest grid price. Displayed prices are never negative.
+    pub fn snap(&self, price: f64) -> f64 {
+        if self.step <= 0.0 {
+            return price.max(0.0);
+        }
+        let lowest = (-self.ending / self.step).ceil();
+        let k = ((price - self.ending) / self.step).round().max(lowest);
+        // drops float noise such as 0.5700000000000001
+        ((k * self.step + self.ending) * 1e9).round() / 1e9
+    }
+
+    /// Closest grid price within `[min_price, max_price]`, or the closest grid
+    /// price overall if no grid price lies in the interval.
+    pub fn snap_within(&self, price: f64, min_price: f64, max_price: f64) -> f64 {
+        let snapped = self.snap(price.clamp(min_price, max_price));
+        if snapped > max_price && snapped - self.step >= min_price {
+            self.snap(snapped - self.step)
+        } else if snapped < min_price && snapped + self.step <= max_price {
+            self.snap(snapped + self.step)
+        } else {
+            snapped
+        }
+    }
+
+    /// `n` evenly spaced prices over `[min_price, max_price]` moved onto the grid.
+    /// Prices that land on the same grid price are kept once, so a coarse grid
+    /// can give fewer than `n` prices.
+    pub fn prices(&self, min_price: f64, max_price: f64, n: usize) -> Vec<f64> {
+        let mut prices: Vec<f64> = (0..n)
+            .map(|i| {
+                let share = if n > 1 { i as f64 / (n - 1) as f64 } else { 0.0 };
+                self.snap_within(min_price + (max_price - min_price) * share, min_price, max_price)
+            })
+            .collect();
+        prices.dedup();
+        prices
+    }
+}
+
+impl Default for PriceGrid {
+    fn default() -> Self {
+        Self::exact()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_keeps_prices_but_not_negative_ones() {
+        let grid = PriceGrid::exact();
+        assert_eq!(grid.snap(12.345), 12.345);
+        assert_eq!(grid.snap(-3.0), 0.0);
+    }
+
+    #[test]
+    fn cents_drop_float_noise() {
+        let grid = PriceGrid::cents();
+        assert_eq!(grid.snap(0.57), 0.57);
+        assert_eq!(grid.snap(0.1 + 0.2), 0.3);
+        assert_eq!(grid.snap(19.994), 19.99);
+    }
+
+    #[test]
+    fn charm_prices_near_zero_are_never_negative() {
+        let grid = PriceGrid::charm(0.99);
+        assert_eq!(grid.snap(0.0), 0.99);
+        assert_eq!(grid.snap(-5.0), 0.99);
+        assert_eq!(grid.snap(0.4), 0.99);
+        assert_eq!(grid.snap(5.2), 4.99);
+        assert_eq!(grid.snap(5.6), 5.99);
+    }
+
+    #[test]
+    fn snap_within_steps_down_when_max_price_is_off_the_grid() {
+        let grid = PriceGrid::increments(10.0);
+        // 97 snaps to 100, above the maximum
+        assert_eq!(grid.snap_within(97.0, 0.0, 97.0), 90.0);
+        assert_eq!(grid.snap_within(200.0, 0.0, 97.0), 90.0);
+        assert_eq!(grid.snap_within(-4.0, 3.0, 97.0), 10.0);
+
+        let charm = PriceGrid::charm(0.99);
+        assert_eq!(charm.snap_within(10.0, 0.0, 10.0), 9.99);
+    }
+
+    #[test]
+    fn snap_within_falls_back_when_no_grid_price_fits() {
+        let grid = PriceGrid::increments(10.0);
+        assert_eq!(grid.snap_within(4.0, 2.0, 8.0), 0.0);
+        assert_eq!(grid.snap_within(7.0, 2.0, 8.0), 10.0);
+    }
+
+    #[test]
+    fn prices_stay_within_bounds() {
+        let prices = PriceGrid::charm(0.99).prices(0.0, 100.0, 30);
+        assert_eq!(prices.first(), Some(&0.99));
+        assert_eq!(prices.last(), Some(&99.99));
+        assert!(prices.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn coarse_grid_shrinks_the_action_space() {
+        let prices = PriceGrid::increments(100.0).prices(0.0, 700.0, 30);
+        assert_eq!(prices, vec![0.0, 100.0, 200.0, 300.0, 400.0, 500.0, 600.0, 700.0]);
+
+        assert_eq!(PriceGrid::exact().prices(0.0, 700.0, 30).len(), 30);
+        assert_eq!(PriceGrid::cents().prices(5.0, 5.0, 4), vec![5.0]);
+    }
+}
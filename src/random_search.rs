@@ -1,22 +1,23 @@
 use crate::simulation::{simulate_revenue, ProblemSettings, SimulationResult};
-use crate::mab::Algorithm;
+use crate::mab::{Algorithm, Outcome, PricingContext};
 use rand::Rng;
 use std::collections::HashMap;
 use std::fs;
+use std::sync::Arc;
 
 #[derive(Clone, Debug)]
-pub struct RandomSearchIndividual<'a> {
+pub struct RandomSearchIndividual {
     pub prices: HashMap<usize, HashMap<usize, Vec<f64>>>,
     pub fitness_score: f64,
-    pub simulation_result: SimulationResult<'a>,
+    pub simulation_result: SimulationResult,
 }
 
-impl<'a> RandomSearchIndividual<'a> {
+impl RandomSearchIndividual {
     pub fn new(
         n_groups: usize,
         n_visits: usize,
         n_periods: usize,
-        settings: &'a ProblemSettings,
+        settings: &Arc<ProblemSettings>,
     ) -> Self {
         let mut rng = rand::thread_rng();
         let mut prices = HashMap::new();
@@ -26,7 +27,8 @@ impl<'a> RandomSearchIndividual<'a> {
             for w in 0..n_visits {
                 let mut period_prices = Vec::new();
                 for _ in 0..n_periods {
-                    period_prices.push(rng.gen_range(0.0..settings.max_price)); // Random price between 0 and 100
+                    let price = rng.gen_range(0.0..settings.max_price);
+                    period_prices.push(settings.price_grid.snap_within(price, 0.0, settings.max_price));
                 }
                 group_map.insert(w, period_prices);
             }
@@ -36,15 +38,7 @@ impl<'a> RandomSearchIndividual<'a> {
         let mut individual = Self {
             prices,
             fitness_score: 0.0,
-            simulation_result: SimulationResult {
-                regret: 0.0,
-                n_sold: 0.0,
-                avg_time_sold_at: 0.0,
-                event_history: vec![],
-                revenue: 0.0,
-                avg_regret: 0.0,
-                customers: vec![],
-            },
+            simulation_result: SimulationResult::default(),
         };
 
         let result = simulate_revenue(&mut individual, settings);
@@ -54,29 +48,22 @@ impl<'a> RandomSearchIndividual<'a> {
     }
 }
 
-impl<'a> Algorithm for RandomSearchIndividual<'a> {
-    fn get_price(&mut self, group_id: usize, visit: usize, period: usize) -> i32 {
-        self.prices[&group_id][&visit][period] as i32
+impl Algorithm for RandomSearchIndividual {
+    fn get_price(&mut self, context: &PricingContext) -> f64 {
+        self.prices[&context.group_id][&context.visit][context.period]
     }
 
-    fn update_average_reward(
-        &mut self,
-        _group_id: usize,
-        _visit: usize,
-        _period: usize,
-        _reward: f64,
-        _price: i32,
-    ) {
+    fn update(&mut self, _context: &PricingContext, _outcome: Outcome) {
         // Random search does not update prices based on rewards
     }
 }
 
-pub fn random_search<'a>(
-    settings: &'a ProblemSettings,
+pub fn random_search(
+    settings: &Arc<ProblemSettings>,
     n_iterations: usize,
-) -> RandomSearchIndividual<'a> {
+) -> RandomSearchIndividual {
     let mut best_individual = RandomSearchIndividual::new(
-        settings.n_groups as usize,
+        settings.num_predicted_groups as usize,
         settings.n_visits as usize,
         settings.n_periods as usize,
         settings,
@@ -87,7 +74,6 @@ pub fn random_search<'a>(
     });
 
     let file = std::fs::OpenOptions::new()
-        .write(true)
         .create(true)
         .append(true)
         .open("./results/random_search_log.csv")
@@ -95,7 +81,7 @@ pub fn random_search<'a>(
     let mut writer = csv::Writer::from_writer(file);
 
     writer
-        .write_record(&[
+        .write_record([
             "evaluation",
             "fitness",
         ])
@@ -103,7 +89,7 @@ pub fn random_search<'a>(
 
     for iteration in 0..n_iterations {
         let candidate = RandomSearchIndividual::new(
-            settings.n_groups as usize,
+            settings.num_predicted_groups as usize,
             settings.n_visits as usize,
             settings.n_periods as usize,
             settings,
@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use std::fs::File;
+
+use rand::Rng;
+
+use crate::mab::{Algorithm, Outcome, PricingContext};
+use crate::simulation::{ProblemSettings, SimulationResult};
+
+/// Which temporal-difference target the tabular agent learns from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RLStrategy {
+    QLearning, // off-policy, bootstraps from the best price of the next state
+    SARSA,     // on-policy, bootstraps from the price actually offered next
+}
+
+impl std::fmt::Display for RLStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RLStrategy::QLearning => write!(f, "QLearning"),
+            RLStrategy::SARSA => write!(f, "SARSA"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RLSettings {
+    pub min_price: f64,
+    pub max_price: f64,
+    pub n_actions: usize,
+    pub strategy: RLStrategy,
+    pub learning_rate: f64,
+    pub discount: f64,           // weight of the customer's next offer, 0 = myopic like the bandits
+    pub epsilon: f64,            // initial exploration rate, decays linearly over n_runs
+    pub final_epsilon: f64,
+    pub n_runs: usize,           // at least 1, a single run keeps the initial epsilon
+    pub initial_value: f64,      // value of unseen prices, optimism also inflates the bootstrapped targets
+    pub period_bucket: usize,    // periods that share a state
+    pub n_reference_bins: usize, // bins of the last price offered to the customer
+}
+
+impl RLSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.n_actions == 0 {
+            return Err("n_actions is 0, expected at least 1".to_string());
+        }
+        // bin 0 is the customer without an offer, the others split the price range
+        if self.n_reference_bins == 0 {
+            return Err("n_reference_bins is 0, expected at least 1".to_string());
+        }
+        if self.n_runs == 0 {
+            return Err("n_runs is 0, expected at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// What the agent knows about a customer when pricing an offer. The last price
+/// offered to the customer stands in for their reference price, which the
+/// platform cannot observe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct State {
+    pub group: usize,
+    pub visit: usize,
+    pub period_bucket: usize,
+    pub reference_bin: usize, // 0 = no offer yet
+}
+
+#[derive(Clone, Debug)]
+struct StateValues {
+    q: Vec<f64>,
+    visits: Vec<usize>,
+}
+
+/// Tabular Q-learning / SARSA over the offers made to a customer. The offers to
+/// one customer form a trajectory: the price of an offer moves the customer's
+/// reference price and so their willingness to pay at the next visit, which
+/// the agent learns through the value of the next state. A trajectory ends
+/// when the customer quits or is not offered a price again before the horizon.
+/// An outcome that arrives after the customer's next offer, with an attribution
+/// window, bootstraps from that offer.
+pub struct TabularAgent {
+    settings: RLSettings,
+    actions: Vec<f64>,
+    n_visits: usize,
+    values: HashMap<State, StateValues>,
+    last_choice: Option<(usize, State, usize)>,   // customer, state and action of the last get_price
+    pending: HashMap<usize, (State, usize)>,      // offer_id -> state and action, until the outcome is known
+    open: HashMap<usize, usize>,                  // customer -> offer_id of their offer without an outcome yet
+    successors: HashMap<usize, (State, usize)>,   // offer_id -> state and action of the customer's next offer
+    waiting: HashMap<usize, (State, usize, f64)>, // customer -> last transition, until the next state is known
+    frozen: bool,
+    pub run_id: usize,
+    pub n_updates: usize,
+}
+
+impl TabularAgent {
+    pub fn new(settings: &ProblemSettings, algorithm_settings: &RLSettings) -> Self {
+        algorithm_settings.validate().unwrap();
+        let actions = settings.price_grid.prices(
+            algorithm_settings.min_price,
+            algorithm_settings.max_price,
+            algorithm_settings.n_actions,
+        );
+        Self {
+            settings: algorithm_settings.clone(),
+            actions,
+            n_visits: settings.n_visits as usize,
+            values: HashMap::new(),
+            last_choice: None,
+            pending: HashMap::new(),
+            open: HashMap::new(),
+            successors: HashMap::new(),
+            waiting: HashMap::new(),
+            frozen: false,
+            run_id: 0,
+            n_updates: 0,
+        }
+    }
+
+    pub fn state(&self, context: &PricingContext) -> State {
+        let reference_bin = match context.history.last_offer {
+            None => 0,
+            Some(price) => {
+                let share = price / self.settings.max_price;
+                1 + ((share * self.settings.n_reference_bins as f64) as usize).min(self.settings.n_reference_bins - 1)
+            }
+        };
+        State {
+            group: context.group_id,
+            visit: context.visit.min(self.n_visits - 1),
+            period_bucket: context.period / self.settings.period_bucket.max(1),
+            reference_bin,
+        }
+    }
+
+    fn values_mut(&mut self, state: State) -> &mut StateValues {
+        let n_actions = self.actions.len();
+        let initial_value = self.settings.initial_value;
+        self.values.entry(state).or_insert_with(|| StateValues {
+            q: vec![initial_value; n_actions],
+            visits: vec![0; n_actions],
+        })
+    }
+
+    fn q(&self, state: &State, action: usize) -> f64 {
+        self.values.get(state).map_or(self.settings.initial_value, |values| values.q[action])
+    }
+
+    // Action with the highest value, ties broken at random
+    fn greedy(&self, state: &State) -> usize {
+        let Some(values) = self.values.get(state) else {
+            return rand::thread_rng().gen_range(0..self.actions.len());
+        };
+        let best = values.q.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let ties: Vec<usize> = (0..values.q.len()).filter(|action| values.q[*action] == best).collect();
+        ties[rand::thread_rng().gen_range(0..ties.len())]
+    }
+
+    fn current_epsilon(&self) -> f64 {
+        if self.settings.n_runs <= 1 {
+            return self.settings.epsilon;
+        }
+        let progress = self.run_id as f64 / (self.settings.n_runs - 1) as f64;
+        self.settings.epsilon - progress * (self.settings.epsilon - self.settings.final_epsilon)
+    }
+
+    fn learn(&mut self, state: State, action: usize, target: f64) {
+        let learning_rate = self.settings.learning_rate;
+        let values = self.values_mut(state);
+        values.q[action] += learning_rate * (target - values.q[action]);
+        values.visits[action] += 1;
+        self.n_updates += 1;
+    }
+
+    fn learn_outcome(&mut self, customer: usize, state: State, action: usize, outcome: Outcome) {
+        match outcome {
+            // the customer leaves for good, nothing to bootstrap from
+            Outcome::Quit { .. } => self.learn(state, action, outcome.reward()),
+            Outcome::Sold { .. } | Outcome::NoPurchase { .. } | Outcome::Converted { .. } => {
+                self.waiting.insert(customer, (state, action, immediate_reward(outcome)));
+            }
+        }
+    }
+
+    // Value of the next state, of its best price or, for SARSA, of the price offered
+    fn next_value(&self, next_state: &State, next_action: usize) -> f64 {
+        match self.settings.strategy {
+            RLStrategy::QLearning => (0..self.actions.len())
+                .map(|action| self.q(next_state, action))
+                .fold(f64::NEG_INFINITY, f64::max),
+            RLStrategy::SARSA => self.q(next_state, next_action),
+        }
+    }
+
+    // Bootstrapped target of the customer's previous offer, now that the state
+    // (and, for SARSA, the price) of their next offer is known
+    fn close_transition(&mut self, customer: usize, next_state: &State, next_action: usize) {
+        if let Some((state, action, reward)) = self.waiting.remove(&customer) {
+            let target = reward + self.settings.discount * self.next_value(next_state, next_action);
+            self.learn(state, action, target);
+        }
+    }
+
+    /// Greedy price and its value for every state visited so far.
+    pub fn policy(&self) -> Vec<(State, f64, f64)> {
+        let mut policy: Vec<(State, f64, f64)> = self
+            .values
+            .iter()
+            .map(|(state, values)| {
+                let (action, value) = values
+                    .q
+                    .iter()
+                    .enumerate()
+                    .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+                    .unwrap();
+                (*state, self.actions[action], *value)
+            })
+            .collect();
+        policy.sort_by_key(|(state, _, _)| (state.group, state.visit, state.period_bucket, state.reference_bin));
+        policy
+    }
+
+    pub fn log(&self, writer: &mut csv::Writer<File>) {
+        for (state, price, value) in self.policy() {
+            let visits: usize = self.values[&state].visits.iter().sum();
+            writer
+                .write_record(&[
+                    self.settings.strategy.to_string(),
+                    state.group.to_string(),
+                    state.visit.to_string(),
+                    (state.period_bucket * self.settings.period_bucket.max(1)).to_string(),
+                    state.reference_bin.to_string(),
+                    price.to_string(),
+                    value.to_string(),
+                    visits.to_string(),
+                ])
+                .unwrap();
+        }
+    }
+}
+
+// Reward of the offer itself: a delayed purchase is the reward of the customer's
+// next offer, which the offer bootstraps from
+fn immediate_reward(outcome: Outcome) -> f64 {
+    match outcome {
+        Outcome::Converted { .. } => 0.0,
+        _ => outcome.reward(),
+    }
+}
+
+impl Algorithm for TabularAgent {
+    fn on_episode_start(&mut self) {
+        self.waiting.clear();
+        self.open.clear();
+        self.successors.clear();
+    }
+
+    fn on_episode_end(&mut self, _result: &SimulationResult) {
+        if self.frozen {
+            return;
+        }
+        // customers not offered a price again before the horizon end their trajectory
+        let waiting: Vec<(State, usize, f64)> = self.waiting.drain().map(|(_, transition)| transition).collect();
+        for (state, action, reward) in waiting {
+            self.learn(state, action, reward);
+        }
+        if self.run_id < self.settings.n_runs {
+            self.run_id += 1;
+        }
+    }
+
+    fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+        self.last_choice = None;
+    }
+
+    fn get_price(&mut self, context: &PricingContext) -> f64 {
+        let state = self.state(context);
+        if self.frozen {
+            return self.actions[self.greedy(&state)];
+        }
+        let action = if rand::thread_rng().gen::<f64>() < self.current_epsilon() {
+            rand::thread_rng().gen_range(0..self.actions.len())
+        } else {
+            self.greedy(&state)
+        };
+        self.close_transition(context.customer, &state, action);
+        if let Some(offer_id) = self.open.get(&context.customer) {
+            self.successors.insert(*offer_id, (state, action));
+        }
+        self.last_choice = Some((context.customer, state, action));
+        self.actions[action]
+    }
+
+    fn register_offer(&mut self, offer_id: usize) {
+        if let Some((customer, state, action)) = self.last_choice.take() {
+            self.pending.insert(offer_id, (state, action));
+            self.open.insert(customer, offer_id);
+        }
+    }
+
+    fn attribute_outcome(&mut self, offer_id: usize, context: &PricingContext, outcome: Option<Outcome>) {
+        if self.open.get(&context.customer) == Some(&offer_id) {
+            self.open.remove(&context.customer);
+        }
+        let successor = self.successors.remove(&offer_id);
+        let (Some((state, action)), Some(outcome)) = (self.pending.remove(&offer_id), outcome) else {
+            return;
+        };
+        match successor {
+            // the customer was offered a price again before this outcome arrived
+            Some((next_state, next_action)) => {
+                let next_value = self.next_value(&next_state, next_action);
+                self.learn(state, action, immediate_reward(outcome) + self.settings.discount * next_value);
+            }
+            None => self.learn_outcome(context.customer, state, action, outcome),
+        }
+    }
+
+    fn update(&mut self, context: &PricingContext, outcome: Outcome) {
+        if let Some((_, state, action)) = self.last_choice.take() {
+            self.learn_outcome(context.customer, state, action, outcome);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::tests::problem_settings;
+    use crate::simulation::CustomerObservations;
+
+    fn rl_settings(strategy: RLStrategy) -> RLSettings {
+        RLSettings {
+            min_price: 100.0,
+            max_price: 400.0,
+            n_actions: 4,
+            strategy,
+            learning_rate: 1.0,
+            discount: 0.5,
+            epsilon: 0.0,
+            final_epsilon: 0.0,
+            n_runs: 10,
+            initial_value: 0.0,
+            period_bucket: 5,
+            n_reference_bins: 4,
+        }
+    }
+
+    fn context(customer: usize, visit: usize, period: usize, last_offer: Option<f64>) -> PricingContext {
+        PricingContext {
+            customer,
+            group_id: 1,
+            visit,
+            t: period as f64,
+            period,
+            history: CustomerObservations { last_offer, ..Default::default() },
+        }
+    }
+
+    #[test]
+    fn state_bins_the_last_offer_and_caps_the_visit() {
+        let agent = TabularAgent::new(&problem_settings(), &rl_settings(RLStrategy::QLearning));
+        let state = |visit, period, last_offer| agent.state(&context(0, visit, period, last_offer));
+        assert_eq!(state(0, 7, None), State { group: 1, visit: 0, period_bucket: 1, reference_bin: 0 });
+        assert_eq!(state(5, 0, Some(0.0)).visit, 2);
+        assert_eq!(state(0, 0, Some(0.0)).reference_bin, 1);
+        assert_eq!(state(0, 0, Some(250.0)).reference_bin, 3);
+        // prices at or above max_price fall into the last bin
+        assert_eq!(state(0, 0, Some(400.0)).reference_bin, 4);
+        assert_eq!(state(0, 0, Some(900.0)).reference_bin, 4);
+    }
+
+    #[test]
+    fn epsilon_decays_linearly_over_the_runs() {
+        let mut settings = rl_settings(RLStrategy::QLearning);
+        settings.epsilon = 0.5;
+        settings.final_epsilon = 0.05;
+        let mut agent = TabularAgent::new(&problem_settings(), &settings);
+        assert_eq!(agent.current_epsilon(), 0.5);
+        agent.run_id = 9;
+        assert!((agent.current_epsilon() - 0.05).abs() < 1e-12);
+
+        settings.n_runs = 1;
+        let agent = TabularAgent::new(&problem_settings(), &settings);
+        assert_eq!(agent.current_epsilon(), 0.5);
+    }
+
+    #[test]
+    fn settings_without_bins_runs_or_actions_are_rejected() {
+        assert!(rl_settings(RLStrategy::SARSA).validate().is_ok());
+        for change in [
+            (|settings: &mut RLSettings| settings.n_reference_bins = 0) as fn(&mut RLSettings),
+            |settings| settings.n_runs = 0,
+            |settings| settings.n_actions = 0,
+        ] {
+            let mut settings = rl_settings(RLStrategy::SARSA);
+            change(&mut settings);
+            assert!(settings.validate().is_err());
+        }
+    }
+
+    // Value the customer's previous offer learns once their next offer, at the
+    // second best price of the next state, is known
+    fn bootstrapped_value(strategy: RLStrategy) -> f64 {
+        let mut agent = TabularAgent::new(&problem_settings(), &rl_settings(strategy));
+        let state = agent.state(&context(0, 0, 0, None));
+        let next_state = agent.state(&context(0, 1, 0, Some(100.0)));
+        agent.values_mut(next_state).q = vec![40.0, 10.0, 0.0, 0.0];
+        agent.waiting.insert(0, (state, 2, 3.0));
+        agent.close_transition(0, &next_state, 1);
+        agent.q(&state, 2)
+    }
+
+    #[test]
+    fn q_learning_bootstraps_from_the_best_price_and_sarsa_from_the_offered_one() {
+        assert_eq!(bootstrapped_value(RLStrategy::QLearning), 3.0 + 0.5 * 40.0);
+        assert_eq!(bootstrapped_value(RLStrategy::SARSA), 3.0 + 0.5 * 10.0);
+    }
+
+    #[test]
+    fn a_quit_ends_the_trajectory() {
+        let mut agent = TabularAgent::new(&problem_settings(), &rl_settings(RLStrategy::QLearning));
+        let first = context(0, 0, 0, None);
+        let price = agent.get_price(&first);
+        let action = agent.actions.iter().position(|p| *p == price).unwrap();
+        agent.register_offer(0);
+        agent.attribute_outcome(0, &first, Some(Outcome::Quit { price }));
+        assert!(agent.waiting.is_empty());
+        assert_eq!(agent.q(&agent.state(&first), action), 0.0);
+        assert_eq!(agent.n_updates, 1);
+    }
+}
@@ -1,13 +1,19 @@
-use crate::{custom, evolution::ESSettings, network_formation::create_network};
+use crate::clustering::{resegment_customers, segment_customers, Resegmentation, Segmentation};
+use crate::event_sink::{EventSink, NoopSink};
+use crate::kpi::KpiBreakdown;
+use crate::network_formation::create_network;
+use crate::price_grid::PriceGrid;
+use crate::welfare::{WelfareMetrics, WelfareTracker};
 use ordered_float::OrderedFloat;
-use priority_queue::PriorityQueue;
-use rand::{rngs::ThreadRng, Rng};
-use rand_distr::{Beta, Exp, Normal};
-use std::cmp::Reverse;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Exp, Normal};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::Arc;
 
-use crate::mab::Algorithm;
+use crate::mab::{Algorithm, Outcome, PricingContext};
 #[derive(Debug, Clone)]
-pub struct Customer<'a> {
+pub struct Customer {
     id: i32,              // unique identifier for the customer
     group: i32,           // true underlying group to which the customer belongs
     predicted_group: i32, // group to which the customer is predicted to belong based on clustering
@@ -17,21 +23,69 @@ pub struct Customer<'a> {
     wtp: f64,             // willingness to pay
     max_wtp: f64,         // maximum willingness to pay
     price_hist: Vec<f64>, // history of prices
-    settings: &'a ProblemSettings,
     pub neighbors: Vec<i32>, // list of the ids of neighboring customers
-    initial_wtp: f64,
+    pub observations: CustomerObservations, // what the platform has seen of this customer
 }
 
-#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+/// Everything the platform can observe about a customer during a run.
+#[derive(Debug, Clone, Default)]
+pub struct CustomerObservations {
+    pub visits: usize,
+    pub first_visit: f32,
+    pub last_visit: f32,
+    pub n_purchases: usize,
+    pub purchase_price_sum: f64,
+    pub n_declined: usize,
+    pub declined_price_sum: f64,
+    pub last_offer: Option<f64>,
+}
+
+impl CustomerObservations {
+    pub fn record_offer(&mut self, t: f32, price: f64, accepted: bool) {
+        if self.visits == 0 {
+            self.first_visit = t;
+        }
+        self.visits += 1;
+        self.last_visit = t;
+        self.last_offer = Some(price);
+        if accepted {
+            self.n_purchases += 1;
+            self.purchase_price_sum += price;
+        } else {
+            self.n_declined += 1;
+            self.declined_price_sum += price;
+        }
+    }
+
+    /// Features used for re-segmentation: visits, mean inter-arrival time,
+    /// share of offers accepted, mean declined price and mean price paid.
+    pub fn features(&self) -> Vec<f64> {
+        let mean = |sum: f64, n: usize| if n > 0 { sum / n as f64 } else { 0.0 };
+        let mean_interarrival = if self.visits > 1 {
+            (self.last_visit - self.first_visit) as f64 / (self.visits - 1) as f64
+        } else {
+            0.0
+        };
+        vec![
+            self.visits as f64,
+            mean_interarrival,
+            self.n_purchases as f64 / self.visits.max(1) as f64,
+            mean(self.declined_price_sum, self.n_declined),
+            mean(self.purchase_price_sum, self.n_purchases),
+        ]
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct SimulationEvent {
     pub t: OrderedFloat<f32>,
-    pub event: String,
+    pub event: &'static str,
     pub customer: i32,
     pub customer_wtp: i32,
     pub customer_max_wtp: i32,
     pub predicted_group: i32,
     pub actual_group: i32,
-    pub price: i32,
+    pub price: f64,
     pub irp: i32,
     pub erp: i32,
     pub rp: i32,
@@ -39,7 +93,7 @@ pub struct SimulationEvent {
 }
 
 impl SimulationEvent {
-    pub fn new(customer: &Customer, t: OrderedFloat<f32>, event: String, price: f64, adjusted_wtp:f64) -> Self {
+    pub fn new(customer: &Customer, t: OrderedFloat<f32>, event: &'static str, price: f64, adjusted_wtp: f64) -> Self {
         SimulationEvent {
             t,
             event,
@@ -51,20 +105,19 @@ impl SimulationEvent {
             rp: customer.rp as i32,
             actual_group: customer.group,
             predicted_group: customer.predicted_group,
-            price: price as i32,
+            price,
             adjusted_wtp: adjusted_wtp as i32,
         }
     }
 }
 
-impl<'a> Customer<'a> {
+impl Customer {
     pub fn new(
         id: i32,
         group: i32,
         predicted_group: i32,
         wtp: f64,
         max_wtp: f64,
-        settings: &'a ProblemSettings,
         neighbors: Vec<i32>,
     ) -> Self {
         Customer {
@@ -78,41 +131,53 @@ impl<'a> Customer<'a> {
             max_wtp,
             price_hist: vec![],
             // visit_hist: vec![],
-            settings,
             neighbors,
-            initial_wtp: wtp,
+            observations: CustomerObservations::default(),
         }
     }
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn group(&self) -> i32 {
+        self.group
+    }
+
+    pub fn predicted_group(&self) -> i32 {
+        self.predicted_group
+    }
+
+    pub fn wtp(&self) -> f64 {
+        self.wtp
+    }
+
     // LABEL
-    pub fn update_irp(&mut self, new_price: f64) {
-        self.irp = self.settings.tau * new_price + self.irp * (1.0 - self.settings.tau)
+    pub fn update_irp(&mut self, new_price: f64, settings: &ProblemSettings) {
+        self.irp = settings.tau * new_price + self.irp * (1.0 - settings.tau)
     }
 
-    // TODO: implement aggregation via network.
     // LABEL
-    pub fn update_erp(&mut self, other_customers: &Vec<Customer>) {
-        let mut ref_prices: Vec<f64> = vec![];
-        for &neighbor_id in &self.neighbors {
-            if let Some(&price) = other_customers[neighbor_id as usize].price_hist.last() {
-                ref_prices.push(price);
-            } else {
-                ref_prices.push(other_customers[neighbor_id as usize].wtp);
-            }
+    /// Average of the neighbours' last purchase prices (or their wtp if they
+    /// have not bought yet). Reads the other customers in place, so callers can
+    /// compute it before taking a mutable borrow of this customer.
+    pub fn neighbor_price(&self, customers: &[Customer]) -> Option<f64> {
+        if self.neighbors.is_empty() {
+            return None;
         }
-        // for customer in other_customers {
-        //     if customer.id == self.id || customer.group == self.group {
-        //         continue;
-        //     } else {
-        //         if rand::thread_rng().gen::<f64>() < self.settings.global_wom_prob {
-        //             ref_prices.push(customer.wtp);
-        //         }
-        //     }
-        // }
-
-        // Calculate average of reference prices and update erp
-        if !ref_prices.is_empty() {
-            let avg_price = ref_prices.iter().sum::<f64>() / ref_prices.len() as f64;
-            // println!("Customer {}: wtp: {}, avg_price: {}, ref_prices: {:?}", self.id, self.wtp as i32, avg_price as i32, ref_prices);
+        let total: f64 = self
+            .neighbors
+            .iter()
+            .map(|&neighbor_id| {
+                let neighbor = &customers[neighbor_id as usize];
+                neighbor.price_hist.last().copied().unwrap_or(neighbor.wtp)
+            })
+            .sum();
+        Some(total / self.neighbors.len() as f64)
+    }
+
+    // LABEL
+    pub fn update_erp(&mut self, neighbor_price: Option<f64>) {
+        if let Some(avg_price) = neighbor_price {
             self.erp = avg_price;
         } else if self.erp < 0.0 {
             // Initialize erp if it hasn't been set yet
@@ -120,29 +185,29 @@ impl<'a> Customer<'a> {
         }
     }
     // LABEL
-    pub fn update_rp(&mut self) {
+    pub fn update_rp(&mut self, settings: &ProblemSettings) {
         if self.erp > self.irp {
             self.rp = self.irp;
         } else {
-            let rp_after = self.settings.eta * self.erp + (1.0 - self.settings.eta) * self.irp;
+            let rp_after = settings.eta * self.erp + (1.0 - settings.eta) * self.irp;
             // println!("rp: {}, erp: {}, irp: {}, rp_after: {}", self.rp, self.erp, self.irp, rp_after);
             self.rp = rp_after
         }
     }
 
     // LABEL
-    pub fn update_wtp(&mut self) {
+    pub fn update_wtp(&mut self, settings: &ProblemSettings) {
         // println!("rp: {}, wtp: {}", self.rp, self.wtp);
         if self.rp > self.wtp {
-            self.wtp += (self.rp - self.wtp).powf(self.settings.alpha);
+            self.wtp += (self.rp - self.wtp).powf(settings.alpha);
         } else {
-            self.wtp -= self.settings.lambda * (self.wtp - self.rp).powf(self.settings.alpha);
+            self.wtp -= settings.lambda * (self.wtp - self.rp).powf(settings.alpha);
         }
     }
 
     // LABEL
     // TODO: fix
-    pub fn next_visit(&self, rng: &mut ThreadRng, t: f32, price: f64) -> f32 {
+    pub fn next_visit(&self, rng: &mut impl Rng, t: f32, price: f64) -> f32 {
         // Calculate the relative price difference
         let price_diff = (self.wtp - price) / self.wtp;
 
@@ -156,14 +221,14 @@ impl<'a> Customer<'a> {
         t + rng.sample::<f32, _>(distr)
     }
 
-    pub fn next_wom(&self, rng: &mut ThreadRng, t: f32) -> f32 {
+    pub fn next_wom(&self, rng: &mut impl Rng, t: f32) -> f32 {
 
-        let distr = Exp::new(0.1 as f32).unwrap();
+        let distr = Exp::new(0.1_f32).unwrap();
         t + rng.sample::<f32, _>(distr)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ProblemSettings {
     pub n_visits: i32,         // number of visits
     pub n_periods: i32,        // number of periods
@@ -177,7 +242,6 @@ pub struct ProblemSettings {
     pub lambda: f64, // loss aversion
     pub eta: f64,    // price sensitivity
     pub max_events: i32,
-    pub clustering_accuracy: f64,
     pub k_neighbors: i32,
     pub p_intra: f64,
     pub p_inter: f64,
@@ -185,76 +249,225 @@ pub struct ProblemSettings {
     pub num_predicted_groups: i32,
     pub sigmoid_scale: f64,
     pub wtp_adjustment_amplitude: f64,
+    pub segmentation: Segmentation,
+    pub resegmentation: Option<Resegmentation>,
+    pub observation_window: f64, // periods of history the clustering observes before the run
+    pub wtp_signal_noise: f64,   // relative noise of the observed wtp signal
+    pub attribution_window: Option<f64>, // periods a declined offer waits for a purchase on a later visit, None = none
+    pub price_grid: PriceGrid,           // how the prices set by the algorithm are displayed
+}
+
+impl ProblemSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        // visits beyond the last are priced as the last, so there must be one
+        if self.n_visits < 1 {
+            return Err(format!("n_visits is {}, expected at least 1", self.n_visits));
+        }
+        if let Segmentation::ConfusionMatrix(matrix) = &self.segmentation {
+            matrix.validate(self.n_groups as usize, self.num_predicted_groups as usize)?;
+        }
+        if let Some(resegmentation) = self.resegmentation {
+            // the next re-estimation would be scheduled at the current time forever
+            if resegmentation.interval.is_nan() || resegmentation.interval <= 0.0 {
+                return Err(format!("resegmentation interval is {}, expected a positive value", resegmentation.interval));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Length in periods of the sine seasonality of the wtp.
+pub const SEASON_LENGTH: f64 = 10.0;
+
+/// Customers leave for good when offered more than this multiple of their wtp.
+pub const QUIT_THRESHOLD: f64 = 1.5;
+
+/// Probability that a customer with (seasonally adjusted) wtp `wtp` accepts `price`.
+pub fn purchase_probability(price: f64, wtp: f64, sigmoid_scale: f64) -> f64 {
+    let price_diff_pct = (wtp - price) / wtp;
+    1.0 / (1.0 + (-price_diff_pct * sigmoid_scale).exp())
 }
 
-pub fn init_simulation(
-    customers: &Vec<Customer>,
-) -> PriorityQueue<SimulationEvent, Reverse<OrderedFloat<f32>>> {
-    let mut rng = rand::thread_rng();
+/// Kind of a scheduled event in the calendar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Arrival,   // customer visits the shop and is offered a price
+    Wom,       // customer updates its reference price through word of mouth
+    Resegment, // platform re-estimates the predicted groups, not tied to a customer
+    Attribution, // attribution window of the customer's pending offer may have run out
+}
 
-    let mut event_calendar: PriorityQueue<SimulationEvent, Reverse<OrderedFloat<f32>>> =
-        PriorityQueue::new();
+/// Offer whose outcome the platform has not learned yet.
+#[derive(Debug, Clone)]
+struct PendingOffer {
+    offer_id: usize,
+    context: PricingContext,
+    price: f64,
+    deadline: f32,
+}
 
-    for customer in customers {
-        let event = SimulationEvent::new(
+// Outcome of the pending offer of a customer who came back: a purchase on this
+// visit is the delayed purchase of the earlier offer, anything else declines it
+fn close_previous_offer(algorithm: &mut dyn Algorithm, offer: Option<PendingOffer>, paid: Option<f64>) {
+    if let Some(offer) = offer {
+        let outcome = match paid {
+            Some(paid) => Outcome::Converted { price: offer.price, paid },
+            None => Outcome::NoPurchase { price: offer.price },
+        };
+        algorithm.attribute_outcome(offer.offer_id, &offer.context, Some(outcome));
+    }
+}
+
+/// Random streams of one customer, one per purpose. A policy decision only
+/// changes which draws a customer makes next, never the draws of other
+/// customers or of other purposes, so policies run on the same seed keep
+/// sharing their random numbers after their decisions diverge.
+struct CustomerStreams {
+    purchase: StdRng,
+    visit: StdRng,
+    wom: StdRng,
+}
+
+impl CustomerStreams {
+    fn new(seed: u64) -> Self {
+        let mut seeder = StdRng::seed_from_u64(seed);
+        Self {
+            purchase: StdRng::seed_from_u64(seeder.gen()),
+            visit: StdRng::seed_from_u64(seeder.gen()),
+            wom: StdRng::seed_from_u64(seeder.gen()),
+        }
+    }
+}
+
+/// Lightweight calendar entry. The calendar only needs the firing time and the
+/// customer index; the customer state is looked up when the event fires.
+#[derive(Debug, Clone, Copy)]
+pub struct ScheduledEvent {
+    pub t: OrderedFloat<f32>,
+    pub customer: usize,
+    pub kind: EventKind,
+    pub price: f64, // price offered at the visit that scheduled this event
+    seq: u64,       // insertion order, breaks ties between events at the same time
+}
+
+impl PartialEq for ScheduledEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ScheduledEvent {}
+
+impl PartialOrd for ScheduledEvent {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScheduledEvent {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.t.cmp(&other.t).then(self.seq.cmp(&other.seq))
+    }
+}
+
+/// Min-heap of scheduled events ordered by time.
+#[derive(Debug, Default)]
+pub struct EventCalendar {
+    heap: BinaryHeap<Reverse<ScheduledEvent>>,
+    next_seq: u64,
+}
+
+impl EventCalendar {
+    pub fn with_capacity(capacity: usize) -> Self {
+        EventCalendar {
+            heap: BinaryHeap::with_capacity(capacity),
+            next_seq: 0,
+        }
+    }
+
+    pub fn push(&mut self, t: f32, customer: usize, kind: EventKind, price: f64) {
+        self.heap.push(Reverse(ScheduledEvent {
+            t: OrderedFloat(t),
             customer,
-            OrderedFloat(customer.next_visit(&mut rng, 0.0, 0.0)),
-            "customer_arrival".to_string(),
-            0.0,
-            customer.wtp,
-        );
-        event_calendar.push(event.clone(), Reverse(event.t));
+            kind,
+            price,
+            seq: self.next_seq,
+        }));
+        self.next_seq += 1;
     }
 
-    return event_calendar;
+    pub fn pop(&mut self) -> Option<ScheduledEvent> {
+        self.heap.pop().map(|Reverse(event)| event)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct SimulationResult<'a> {
-    pub regret: f64,
+pub fn init_simulation(customers: &[Customer], rng: &mut impl Rng) -> EventCalendar {
+    let mut event_calendar = EventCalendar::with_capacity(2 * customers.len());
+
+    for (idx, customer) in customers.iter().enumerate() {
+        let t = customer.next_visit(rng, 0.0, 0.0);
+        event_calendar.push(t, idx, EventKind::Arrival, 0.0);
+    }
+
+    event_calendar
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SimulationResult {
+    pub regret: f64, // wtp left on the table, see `oracle` for the regret against achievable policies
     pub n_sold: f64,
     pub avg_time_sold_at: f32,
-    pub event_history: Vec<SimulationEvent>,
     pub revenue: f64,
     pub avg_regret: f64,
-    pub customers: Vec<Customer<'a>>,
+    pub customers: Vec<Customer>, // state at the end of the run
+    pub initial_segmentation_accuracy: f64,
+    pub segmentation_accuracy: f64, // at the end of the run, differs from the initial one with re-segmentation
+    pub n_resegmentations: usize,
+    pub kpis: KpiBreakdown,
+    pub welfare: WelfareMetrics,
 }
 
-pub fn simulate_revenue<'a>(
-    algorithm: &mut dyn Algorithm,
-    settings: &'a ProblemSettings,
-) -> SimulationResult<'a> {
-    let mut rng = rand::thread_rng();
-
-    let mut customers: Vec<Customer> = Vec::new();
+/// Share of customers whose predicted group is their true group.
+pub fn segmentation_accuracy(customers: &[Customer]) -> f64 {
+    customers.iter().filter(|c| c.group == c.predicted_group).count() as f64 / customers.len() as f64
+}
 
-    let beta_dist = Beta::new(3.0, 6.0).unwrap();
+/// Draws the customers of one run, their network and their predicted groups.
+pub fn create_customers(settings: &ProblemSettings, rng: &mut impl Rng) -> Vec<Customer> {
+    let mut customers: Vec<Customer> = Vec::with_capacity(settings.n_customers as usize);
 
-    let network = create_network(settings);
+    settings.validate().unwrap();
+    let network = create_network(settings, rng);
+    let confusion_samplers = match &settings.segmentation {
+        Segmentation::ConfusionMatrix(matrix) => Some(matrix.samplers()),
+        _ => None,
+    };
 
     let mut id = 0;
     for customer_group in 0..settings.group_sizes.len() {
         let group_size = settings.group_sizes[customer_group];
         let group_mean = settings.group_means[customer_group];
+        let normal_dist = Normal::new(
+            group_mean * settings.scaling,
+            (group_mean * settings.scaling * 0.2).powf(0.5),
+        )
+        .unwrap();
         for _ in 0..group_size {
             let neighbors = network[id as usize].clone();
-            let normal_dist = Normal::new(
-                group_mean * settings.scaling,
-                (group_mean * settings.scaling * 0.2).powf(0.5),
-            )
-            .unwrap();
-            let wtp_increase = 2.0; //+ rng.sample(beta_dist);
+            let wtp_increase = 2.0;
             let wtp0: f64 = rng.sample(normal_dist);
 
-            // With X% probability, assign a random group prediction
-            let predicted_group: usize = if rng.gen_bool(1.0-settings.clustering_accuracy) {
-                rng.gen_range(0..(settings.num_predicted_groups as usize))
-            } else {
-                if (customer_group as usize) < settings.num_predicted_groups as usize {
-                    customer_group as usize
-                } else {
-                    rng.gen_range(0..(settings.num_predicted_groups as usize))
-                }
+            let predicted_group: usize = match &confusion_samplers {
+                Some(samplers) => rng.sample(&samplers[customer_group]),
+                None => customer_group, // replaced by the clustering below
             };
 
             customers.push(Customer::new(
@@ -263,168 +476,378 @@ pub fn simulate_revenue<'a>(
                 predicted_group as i32,
                 wtp0,
                 wtp0 * wtp_increase,
-                settings,
                 neighbors,
             ));
-            // total_wtp += wtp0;
             id += 1;
         }
     }
 
-    let mut event_calendar = init_simulation(&customers);
+    if confusion_samplers.is_none() {
+        let predicted_groups = segment_customers(&customers, settings, rng);
+        for (customer, predicted_group) in customers.iter_mut().zip(predicted_groups) {
+            customer.predicted_group = predicted_group as i32;
+        }
+    }
+
+    customers
+}
+
+pub fn simulate_revenue(
+    algorithm: &mut dyn Algorithm,
+    settings: &Arc<ProblemSettings>,
+) -> SimulationResult {
+    simulate_revenue_with_sink(algorithm, settings, &mut NoopSink)
+}
+
+/// Runs one simulation and hands every visit/sold/quit event to `sink`.
+pub fn simulate_revenue_with_sink(
+    algorithm: &mut dyn Algorithm,
+    settings: &Arc<ProblemSettings>,
+    sink: &mut dyn EventSink,
+) -> SimulationResult {
+    simulate_revenue_seeded(algorithm, settings, sink, rand::thread_rng().gen())
+}
+
+/// Runs one simulation with all customer randomness drawn from `seed`. Running
+/// several policies on the same seeds gives common random numbers: the same
+/// population, and per customer the same purchase, visit and word-of-mouth draws.
+pub fn simulate_revenue_seeded(
+    algorithm: &mut dyn Algorithm,
+    settings: &Arc<ProblemSettings>,
+    sink: &mut dyn EventSink,
+    seed: u64,
+) -> SimulationResult {
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut customers = create_customers(settings, &mut rng);
+    let initial_segmentation_accuracy = segmentation_accuracy(&customers);
+
+    let mut event_calendar = init_simulation(&customers, &mut rng);
+    let mut streams: Vec<CustomerStreams> = (0..customers.len()).map(|_| CustomerStreams::new(rng.gen())).collect();
+    let mut resegment_rng = StdRng::seed_from_u64(rng.gen());
+    if let Some(resegmentation) = settings.resegmentation {
+        event_calendar.push(resegmentation.interval as f32, 0, EventKind::Resegment, 0.0);
+    }
+    let mut n_resegmentations = 0;
     let mut revenue = 0.0;
     let mut regret = 0.0;
     let mut n_sold = 0;
     let mut event_count = 0;
     let mut avg_sold_at = 0.0;
-    let mut avg_wtp_increase = 0.0;
-
-    let mut event_history: Vec<SimulationEvent> = Vec::new();
-
-    while !event_calendar.is_empty() && event_count < settings.max_events {
-        event_count += 1;
-        let event = event_calendar.pop();
-        if event.is_none() {
+    let mut kpis = KpiBreakdown::new(settings);
+    let mut welfare = WelfareTracker::new(customers.len());
+    let mut pending: Vec<Option<PendingOffer>> = vec![None; customers.len()];
+    let mut n_offers = 0;
+    let mut current_period = None;
+    algorithm.on_episode_start();
+
+    while event_count < settings.max_events {
+        let Some(event) = event_calendar.pop() else {
+            break;
+        };
+        if event.t > OrderedFloat(settings.n_periods as f32) {
+            // the calendar is ordered by time, every remaining event is past the horizon
             break;
         }
-        let event = event.unwrap();
-        if event.0.t > OrderedFloat(settings.n_periods as f32) {
-            continue;
+        if current_period != Some(event.t.0 as usize) {
+            current_period = Some(event.t.0 as usize);
+            algorithm.on_period_change(event.t.0 as usize);
         }
 
-        // event_history.push(event.0.clone());
-
-        let customer_idx = event.0.customer as usize;
-
-        let visit_index = customers[customer_idx].price_hist.len() as i32;
-        let price = algorithm.get_price(
-            customers[customer_idx].predicted_group as usize,
-            visit_index as usize,
-            // 0,
-            // 0
-            event.0.t.0 as usize,
-            // 0
-        ) as f64;
-        
-        let period = 10.0;
-        let amplitude = settings.wtp_adjustment_amplitude;
-
-        let time_factor = amplitude * ((2.0 * std::f64::consts::PI * event.0.t.0 as f64 / period).sin());
-
-        // println!("Time factor: {}", time_factor);
-        let adjusted_wtp = customers[customer_idx].wtp * (1.0 + time_factor as f64);
-
-        let price_diff_pct = (adjusted_wtp - price) / adjusted_wtp;
-        let purchase_prob = 1.0 / (1.0 + (-price_diff_pct * settings.sigmoid_scale).exp());
-        
-        
-        let customers_copy = customers.to_vec();
+        if event.kind == EventKind::Attribution {
+            // bookkeeping of the platform, does not count towards max_events
+            if pending[event.customer].as_ref().is_some_and(|offer| offer.deadline <= event.t.0) {
+                let offer = pending[event.customer].take().unwrap();
+                let outcome = Outcome::NoPurchase { price: offer.price };
+                algorithm.attribute_outcome(offer.offer_id, &offer.context, Some(outcome));
+            }
+            continue;
+        }
 
-        customers[customer_idx].update_erp(&customers_copy);
+        if event.kind == EventKind::Resegment {
+            // bookkeeping of the platform too, does not count towards max_events
+            let resegmentation = settings.resegmentation.unwrap();
+            for (idx, predicted_group) in resegment_customers(&customers, settings, resegmentation.method, &mut resegment_rng) {
+                customers[idx].predicted_group = predicted_group as i32;
+            }
+            n_resegmentations += 1;
+            event_calendar.push(event.t.0 + resegmentation.interval as f32, 0, EventKind::Resegment, 0.0);
+            continue;
+        }
+        event_count += 1;
 
+        let customer_idx = event.customer;
+        let neighbor_price = customers[customer_idx].neighbor_price(&customers);
+        customers[customer_idx].update_erp(neighbor_price);
 
-        if (event.0.event == "wom") {
-            customers[customer_idx].update_erp(&customers_copy);
-            customers[customer_idx].update_rp();
-            customers[customer_idx].update_wtp();
-            continue
+        if event.kind == EventKind::Wom {
+            customers[customer_idx].update_rp(settings);
+            customers[customer_idx].update_wtp(settings);
+            continue;
         }
 
+        // the customer came back within the attribution window of their previous
+        // offer, which is closed once the outcome of this visit is known
+        let previous_offer = pending[customer_idx].take();
+
+        // offers seen before this one, later visits share the last index
+        let visit_index = customers[customer_idx].observations.visits.min(settings.n_visits as usize - 1);
+        let true_group = customers[customer_idx].group as usize;
+        let predicted_group = customers[customer_idx].predicted_group as usize;
+        let period = event.t.0 as usize;
 
-        if price > adjusted_wtp * 1.5 {
+        let amplitude = settings.wtp_adjustment_amplitude;
+        let time_factor =
+            amplitude * ((2.0 * std::f64::consts::PI * event.t.0 as f64 / SEASON_LENGTH).sin());
+        let adjusted_wtp = customers[customer_idx].wtp * (1.0 + time_factor);
+
+        algorithm.observe_true_state(true_group, adjusted_wtp);
+        let context = PricingContext {
+            customer: customer_idx,
+            group_id: predicted_group,
+            visit: visit_index,
+            t: event.t.0 as f64,
+            period,
+            history: customers[customer_idx].observations.clone(),
+        };
+        // customers see, and pay, the displayed price
+        let price = settings.price_grid.snap(algorithm.get_price(&context));
+        let offer_id = n_offers;
+        n_offers += 1;
+        algorithm.register_offer(offer_id);
+
+        let purchase_prob = purchase_probability(price, adjusted_wtp, settings.sigmoid_scale);
+
+        if price > adjusted_wtp * QUIT_THRESHOLD {
             regret += adjusted_wtp;
-            event_history.push(SimulationEvent::new(
+            kpis.record_visit(true_group, predicted_group, period, adjusted_wtp);
+            kpis.record_quit(true_group, predicted_group, period);
+            customers[customer_idx].observations.record_offer(event.t.0, price, false);
+            welfare.record_quit(customer_idx, customers[customer_idx].wtp);
+            sink.record(SimulationEvent::new(
                 &customers[customer_idx],
-                event.0.t,
-                "quit".to_string(),
+                event.t,
+                "quit",
                 price,
-                adjusted_wtp
+                adjusted_wtp,
             ));
-            algorithm.update_average_reward(
-                customers[customer_idx].predicted_group as usize,
-                visit_index as usize,
-                event.0.t.0 as usize,
-                0.0,
-                price as i32,
-            );
+            // the customer leaves, so the outcome is known right away
+            algorithm.attribute_outcome(offer_id, &context, Some(Outcome::Quit { price }));
+            close_previous_offer(algorithm, previous_offer, None);
             continue;
-        } else if rng.gen::<f64>() < purchase_prob {
+        } else if streams[customer_idx].purchase.gen::<f64>() < purchase_prob {
             revenue += price;
             customers[customer_idx].price_hist.push(price);
+            customers[customer_idx].observations.record_offer(event.t.0, price, true);
             regret += adjusted_wtp - price;
+            kpis.record_visit(true_group, predicted_group, period, adjusted_wtp - price);
+            kpis.record_sale(true_group, predicted_group, period, price);
+            welfare.record_sale(customer_idx, price, adjusted_wtp);
             n_sold += 1;
-            avg_sold_at += event.0.t.0;
-            avg_wtp_increase += adjusted_wtp - customers[customer_idx].initial_wtp;
+            avg_sold_at += event.t.0;
 
             // Update the algorithm with the reward (revenue in this case)
-            algorithm.update_average_reward(
-                customers[customer_idx].predicted_group as usize,
-                visit_index as usize,
-                event.0.t.0 as usize,
-                price,
-                price as i32,
-            );
+            algorithm.attribute_outcome(offer_id, &context, Some(Outcome::Sold { price }));
+            close_previous_offer(algorithm, previous_offer, Some(price));
 
-            event_history.push(SimulationEvent::new(
+            sink.record(SimulationEvent::new(
                 &customers[customer_idx],
-                event.0.t,
-                "sold".to_string(),
+                event.t,
+                "sold",
                 price,
-                adjusted_wtp
+                adjusted_wtp,
             ));
         } else {
-            let next_visit_at =
-                customers[customer_idx].next_visit(&mut rng, event.0.t.0, event.0.price as f64);
-            let next_visit_event = SimulationEvent::new(
-                &customers[customer_idx],
-                OrderedFloat(next_visit_at),
-                "customer_arrival".to_string(),
-                price,
-                adjusted_wtp
-            );
-
-            let next_wom_at =
-                customers[customer_idx].next_wom(&mut rng, event.0.t.0);
-            let next_wom_event = SimulationEvent::new(
-                &customers[customer_idx],
-                OrderedFloat(next_wom_at),
-                "wom".to_string(),
-                price,
-                adjusted_wtp
-            );
-
-            
-
-            // algorithm.update_average_reward(
-            //     customers[customer_idx].predicted_group as usize,
-            //     visit_index as usize,
-            //     event.0.t.0 as usize,
-            //     0.0,
-            //     price as i32,
-            // );
-            event_calendar.push(next_visit_event, Reverse(OrderedFloat(next_visit_at)));
-            event_calendar.push(next_wom_event, Reverse(OrderedFloat(next_wom_at)));
+            kpis.record_visit(true_group, predicted_group, period, 0.0);
+            customers[customer_idx].observations.record_offer(event.t.0, price, false);
+            close_previous_offer(algorithm, previous_offer, None);
+            // An offer that was seen and declined is an outcome too, not only sales and quits,
+            // but with an attribution window the platform only learns it later
+            match settings.attribution_window {
+                None => algorithm.attribute_outcome(offer_id, &context, Some(Outcome::NoPurchase { price })),
+                Some(window) => {
+                    let deadline = event.t.0 + window as f32;
+                    pending[customer_idx] = Some(PendingOffer {
+                        offer_id,
+                        context,
+                        price,
+                        deadline,
+                    });
+                    event_calendar.push(deadline, customer_idx, EventKind::Attribution, price);
+                }
+            }
+            let next_visit_at = customers[customer_idx].next_visit(&mut streams[customer_idx].visit, event.t.0, event.price);
+            let next_wom_at = customers[customer_idx].next_wom(&mut streams[customer_idx].wom, event.t.0);
+            event_calendar.push(next_visit_at, customer_idx, EventKind::Arrival, price);
+            event_calendar.push(next_wom_at, customer_idx, EventKind::Wom, price);
         }
-        customers[customer_idx].update_irp(price);
-        customers[customer_idx].update_rp();
-        customers[customer_idx].update_wtp();
+        customers[customer_idx].update_irp(price, settings);
+        customers[customer_idx].update_rp(settings);
+        customers[customer_idx].update_wtp(settings);
+
+        sink.record(SimulationEvent::new(
+            &customers[customer_idx],
+            event.t,
+            "visit",
+            price,
+            adjusted_wtp,
+        ));
+    }
 
-        event_history.push(SimulationEvent::new(
-                &customers[customer_idx],
-                event.0.t,
-                "visit".to_string(),
-                price,
-                adjusted_wtp
-            ));
+    // offers still unresolved at the horizon are censored
+    for offer in pending.into_iter().flatten() {
+        algorithm.attribute_outcome(offer.offer_id, &offer.context, None);
     }
 
-    return SimulationResult {
+    let welfare = welfare.finish(&customers, settings.n_groups as usize);
+    let segmentation_accuracy = segmentation_accuracy(&customers);
+
+    let result = SimulationResult {
         regret,
         avg_regret: regret / customers.len() as f64,
         n_sold: n_sold as f64 / customers.len() as f64,
         avg_time_sold_at: avg_sold_at / n_sold as f32,
-        event_history,
         revenue,
         customers,
+        initial_segmentation_accuracy,
+        segmentation_accuracy,
+        n_resegmentations,
+        kpis,
+        welfare,
     };
+    algorithm.on_episode_end(&result);
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::clustering::{ClusteringMethod, ConfusionMatrix};
+
+    /// Small market shared by the unit tests: two groups of five customers and
+    /// a short horizon, so a run takes well under a millisecond.
+    pub(crate) fn problem_settings() -> ProblemSettings {
+        ProblemSettings {
+            n_visits: 3,
+            n_periods: 10,
+            n_groups: 2,
+            n_customers: 10,
+            tau: 0.6,
+            scaling: 100.0,
+            group_sizes: vec![5, 5],
+            group_means: vec![2.0, 5.0],
+            alpha: 0.88,
+            lambda: 2.25,
+            eta: 0.5,
+            max_events: 100,
+            k_neighbors: 2,
+            p_intra: 0.2,
+            p_inter: 0.1,
+            max_price: 700.0,
+            num_predicted_groups: 2,
+            sigmoid_scale: 200.0,
+            wtp_adjustment_amplitude: 0.7,
+            segmentation: Segmentation::ConfusionMatrix(ConfusionMatrix::from_accuracy(2, 2, 1.0)),
+            resegmentation: None,
+            observation_window: 100.0,
+            wtp_signal_noise: 0.3,
+            attribution_window: None,
+            price_grid: PriceGrid::cents(),
+        }
+    }
+
+    // Offers prices in turn and records every outcome it is told about
+    #[derive(Default)]
+    struct Recorder {
+        prices: Vec<f64>,
+        offers: Vec<f64>,                              // price of every registered offer
+        outcomes: Vec<(usize, usize, Option<Outcome>)>, // (offer_id, customer, outcome)
+    }
+
+    impl Algorithm for Recorder {
+        fn get_price(&mut self, _context: &PricingContext) -> f64 {
+            self.prices[self.offers.len() % self.prices.len()]
+        }
+
+        fn register_offer(&mut self, offer_id: usize) {
+            assert_eq!(offer_id, self.offers.len());
+            self.offers.push(self.prices[offer_id % self.prices.len()]);
+        }
+
+        fn attribute_outcome(&mut self, offer_id: usize, context: &PricingContext, outcome: Option<Outcome>) {
+            self.outcomes.push((offer_id, context.customer, outcome));
+        }
+
+        fn update(&mut self, _context: &PricingContext, _outcome: Outcome) {}
+    }
+
+    #[test]
+    fn resegmentation_interval_must_be_positive() {
+        let mut settings = problem_settings();
+        assert!(settings.validate().is_ok());
+        for interval in [10.0, 0.0, -1.0, f64::NAN] {
+            settings.resegmentation = Some(Resegmentation { interval, method: ClusteringMethod::KMeans });
+            assert_eq!(settings.validate().is_ok(), interval > 0.0, "interval {}", interval);
+        }
+    }
+
+    #[test]
+    fn at_least_one_visit_is_priced() {
+        let mut settings = problem_settings();
+        settings.n_visits = 1;
+        assert!(settings.validate().is_ok());
+        settings.n_visits = 0;
+        assert!(settings.validate().is_err());
+    }
+
+    fn run_recorder(attribution_window: Option<f64>, seed: u64) -> Recorder {
+        let mut settings = problem_settings();
+        settings.attribution_window = attribution_window;
+        settings.max_events = 1000;
+        let mut recorder = Recorder {
+            prices: vec![180.0, 260.0, 450.0, 620.0],
+            ..Default::default()
+        };
+        simulate_revenue_seeded(&mut recorder, &Arc::new(settings), &mut NoopSink, seed);
+        recorder
+    }
+
+    #[test]
+    fn every_offer_gets_exactly_one_outcome() {
+        for attribution_window in [None, Some(3.0)] {
+            let recorder = run_recorder(attribution_window, 7);
+            let mut offer_ids: Vec<usize> = recorder.outcomes.iter().map(|(offer_id, _, _)| *offer_id).collect();
+            offer_ids.sort_unstable();
+            assert_eq!(offer_ids, (0..recorder.offers.len()).collect::<Vec<_>>());
+            for (offer_id, _, outcome) in &recorder.outcomes {
+                if let Some(outcome) = outcome {
+                    assert_eq!(outcome.price(), recorder.offers[*offer_id]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn later_purchase_is_credited_to_the_pending_offer() {
+        let mut n_converted = 0;
+        for seed in 0..10 {
+            let recorder = run_recorder(Some(100.0), seed);
+            for (offer_id, customer, outcome) in &recorder.outcomes {
+                let Some(Outcome::Converted { paid, .. }) = outcome else {
+                    continue;
+                };
+                n_converted += 1;
+                // the sale that converted the offer is a later offer to the same customer
+                let sale = Some(Outcome::Sold { price: *paid });
+                assert!(recorder.outcomes.iter().any(|(later_id, later_customer, later_outcome)| {
+                    later_id > offer_id && later_customer == customer && *later_outcome == sale
+                }));
+            }
+
+            let immediate = run_recorder(None, seed);
+            let converted = |outcome: &Option<Outcome>| matches!(outcome, Some(Outcome::Converted { .. }));
+            assert!(!immediate.outcomes.iter().any(|(_, _, outcome)| converted(outcome)));
+        }
+        assert!(n_converted > 0);
+    }
 }
@@ -0,0 +1,231 @@
+use std::sync::Arc;
+
+use crate::event_sink::NoopSink;
+use crate::mab::Algorithm;
+use crate::simulation::{simulate_revenue, simulate_revenue_seeded, ProblemSettings, SimulationResult};
+
+pub struct TrainingSettings {
+    pub n_episodes: usize,      // training episodes (simulation runs) at most
+    pub eval_every: usize,      // training episodes between two evaluations
+    pub n_eval_episodes: usize, // frozen episodes per evaluation, on the same seeds every time
+    pub eval_seed: u64,         // first seed of the evaluation episodes
+    pub patience: Option<usize>, // evaluations without improvement before stopping, None = never stop early
+    pub min_improvement: f64,   // relative gain in evaluation revenue that counts as an improvement
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Train,
+    Eval,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::Train => write!(f, "train"),
+            Phase::Eval => write!(f, "eval"),
+        }
+    }
+}
+
+/// One point of a learning curve. Evaluation points average their episodes.
+#[derive(Clone, Debug)]
+pub struct CurvePoint {
+    pub episode: usize, // training episodes done so far
+    pub phase: Phase,
+    pub revenue: f64,
+    pub regret: f64,
+    pub n_sold: f64,
+    pub conversion: f64,
+}
+
+impl CurvePoint {
+    fn new(episode: usize, phase: Phase, results: &[SimulationResult]) -> Self {
+        let n = results.len() as f64;
+        let mean = |value: fn(&SimulationResult) -> f64| results.iter().map(value).sum::<f64>() / n;
+        Self {
+            episode,
+            phase,
+            revenue: mean(|result| result.revenue),
+            regret: mean(|result| result.regret),
+            n_sold: mean(|result| result.n_sold),
+            conversion: mean(|result| {
+                let visits: usize = result.kpis.by_true_group.iter().map(|kpis| kpis.visits).sum();
+                let sold: usize = result.kpis.by_true_group.iter().map(|kpis| kpis.n_sold).sum();
+                if visits > 0 {
+                    sold as f64 / visits as f64
+                } else {
+                    0.0
+                }
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TrainingReport {
+    pub curve: Vec<CurvePoint>,
+    pub episodes: usize,
+    pub best_eval_revenue: f64,
+    pub best_eval_episode: usize,
+    pub stopped_early: bool,
+}
+
+/// Evaluates the frozen policy (no exploration, no learning) on the evaluation seeds.
+pub fn evaluate<A: Algorithm>(
+    algorithm: &mut A,
+    settings: &Arc<ProblemSettings>,
+    training: &TrainingSettings,
+) -> Vec<SimulationResult> {
+    algorithm.set_frozen(true);
+    let results = (0..training.n_eval_episodes)
+        .map(|i| simulate_revenue_seeded(algorithm, settings, &mut NoopSink, training.eval_seed + i as u64))
+        .collect();
+    algorithm.set_frozen(false);
+    results
+}
+
+/// Trains an online learner episode by episode, evaluating the frozen policy
+/// every `eval_every` episodes and after the last one. Stops early when the
+/// evaluation revenue has not improved for `patience` evaluations.
+pub fn train<A: Algorithm>(
+    algorithm: &mut A,
+    settings: &Arc<ProblemSettings>,
+    training: &TrainingSettings,
+) -> TrainingReport {
+    let mut report = TrainingReport {
+        best_eval_revenue: f64::NEG_INFINITY,
+        ..Default::default()
+    };
+    let mut evaluations_without_improvement = 0;
+
+    for episode in 1..=training.n_episodes {
+        let result = simulate_revenue(algorithm, settings);
+        report.curve.push(CurvePoint::new(episode, Phase::Train, &[result]));
+        report.episodes = episode;
+
+        let last = episode == training.n_episodes;
+        if training.n_eval_episodes == 0 || (episode % training.eval_every.max(1) != 0 && !last) {
+            continue;
+        }
+        let point = CurvePoint::new(episode, Phase::Eval, &evaluate(algorithm, settings, training));
+        if point.revenue > report.best_eval_revenue * (1.0 + training.min_improvement)
+            || report.best_eval_revenue == f64::NEG_INFINITY
+        {
+            report.best_eval_revenue = point.revenue;
+            report.best_eval_episode = episode;
+            evaluations_without_improvement = 0;
+        } else {
+            evaluations_without_improvement += 1;
+        }
+        report.curve.push(point);
+
+        if training.patience.is_some_and(|patience| evaluations_without_improvement >= patience) {
+            report.stopped_early = !last;
+            break;
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mab::{Outcome, PricingContext};
+    use crate::simulation::tests::problem_settings;
+
+    /// Offers the i-th scripted price during the i-th evaluation, and the last
+    /// one after the script runs out.
+    struct Scripted {
+        eval_prices: Vec<f64>,
+        n_evaluations: usize,
+        frozen: bool,
+    }
+
+    impl Scripted {
+        fn new(eval_prices: Vec<f64>) -> Self {
+            Self { eval_prices, n_evaluations: 0, frozen: false }
+        }
+    }
+
+    impl Algorithm for Scripted {
+        fn set_frozen(&mut self, frozen: bool) {
+            if frozen {
+                self.n_evaluations += 1;
+            }
+            self.frozen = frozen;
+        }
+
+        fn get_price(&mut self, _context: &PricingContext) -> f64 {
+            if self.frozen {
+                self.eval_prices[(self.n_evaluations - 1).min(self.eval_prices.len() - 1)]
+            } else {
+                250.0
+            }
+        }
+
+        fn update(&mut self, _context: &PricingContext, _outcome: Outcome) {}
+    }
+
+    fn training_settings(patience: Option<usize>, min_improvement: f64) -> TrainingSettings {
+        TrainingSettings {
+            n_episodes: 20,
+            eval_every: 2,
+            n_eval_episodes: 2,
+            eval_seed: 11,
+            patience,
+            min_improvement,
+        }
+    }
+
+    fn eval_revenues(report: &TrainingReport) -> Vec<f64> {
+        report.curve.iter().filter(|point| point.phase == Phase::Eval).map(|point| point.revenue).collect()
+    }
+
+    #[test]
+    fn stops_after_patience_evaluations_without_improvement() {
+        let settings = Arc::new(problem_settings());
+        let report = train(&mut Scripted::new(vec![250.0]), &settings, &training_settings(Some(3), 0.0));
+        // the same price on the same seeds never improves on the first evaluation
+        assert_eq!(eval_revenues(&report).len(), 4);
+        assert_eq!(report.episodes, 8);
+        assert_eq!(report.best_eval_episode, 2);
+        assert!(report.stopped_early);
+    }
+
+    #[test]
+    fn an_improvement_resets_the_patience() {
+        let settings = Arc::new(problem_settings());
+        let mut algorithm = Scripted::new(vec![50.0, 50.0, 250.0]);
+        let report = train(&mut algorithm, &settings, &training_settings(Some(2), 0.0));
+        let revenues = eval_revenues(&report);
+        assert!(revenues[2] > revenues[0]);
+        assert_eq!(report.best_eval_episode, 6);
+        assert_eq!(report.best_eval_revenue, revenues[2]);
+        assert_eq!(report.episodes, 10);
+        assert!(report.stopped_early);
+    }
+
+    #[test]
+    fn gains_below_min_improvement_do_not_count() {
+        let settings = Arc::new(problem_settings());
+        let mut algorithm = Scripted::new(vec![50.0, 250.0]);
+        let revenues = eval_revenues(&train(&mut algorithm, &settings, &training_settings(Some(2), 0.0)));
+        let gain = revenues[1] / revenues[0] - 1.0;
+
+        let mut algorithm = Scripted::new(vec![50.0, 250.0]);
+        let report = train(&mut algorithm, &settings, &training_settings(Some(2), gain + 0.01));
+        assert_eq!(report.best_eval_episode, 2);
+        assert_eq!(report.episodes, 6);
+    }
+
+    #[test]
+    fn without_patience_every_episode_is_trained() {
+        let settings = Arc::new(problem_settings());
+        let report = train(&mut Scripted::new(vec![250.0]), &settings, &training_settings(None, 0.0));
+        assert_eq!(report.episodes, 20);
+        assert_eq!(eval_revenues(&report).len(), 10);
+        assert_eq!(report.curve.len(), 30);
+        assert!(!report.stopped_early);
+    }
+}
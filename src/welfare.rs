@@ -0,0 +1,182 @@
+use crate::simulation::Customer;
+
+/// How a customer left the market.
+#[derive(Debug, Clone, Copy)]
+enum Resolution {
+    Bought { price: f64, wtp: f64 },
+    Quit { wtp: f64 }, // without the seasonal adjustment, when the customer quit
+}
+
+/// Inequality of the prices paid within one true group (or overall).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PriceInequality {
+    pub n_buyers: usize,
+    pub mean_price: f64,
+    pub gini: f64,
+    pub coefficient_of_variation: f64,
+}
+
+impl PriceInequality {
+    pub fn from_prices(prices: &[f64]) -> Self {
+        if prices.is_empty() {
+            return Self::default();
+        }
+        let mean_price = mean(prices);
+        let coefficient_of_variation = if mean_price > 0.0 {
+            std_dev(prices) / mean_price
+        } else {
+            0.0
+        };
+        Self {
+            n_buyers: prices.len(),
+            mean_price,
+            gini: gini(prices),
+            coefficient_of_variation,
+        }
+    }
+}
+
+/// Welfare accounting of a run. Marginal cost is zero, so the producer surplus
+/// equals the revenue.
+#[derive(Debug, Clone, Default)]
+pub struct WelfareMetrics {
+    pub consumer_surplus: f64,  // wtp minus price, summed over buyers
+    pub producer_surplus: f64,
+    pub deadweight_loss: f64,   // wtp, without the seasonal adjustment, of customers who quit or never bought
+    pub total_welfare: f64,     // consumer plus producer surplus
+    pub price_dispersion: f64,  // standard deviation of the prices paid across customers
+    pub inequality: PriceInequality,
+    pub inequality_by_group: Vec<PriceInequality>, // indexed by true group
+}
+
+/// Collects how every customer left the market during a simulation.
+#[derive(Debug, Clone)]
+pub struct WelfareTracker {
+    resolutions: Vec<Option<Resolution>>,
+}
+
+impl WelfareTracker {
+    pub fn new(n_customers: usize) -> Self {
+        Self {
+            resolutions: vec![None; n_customers],
+        }
+    }
+
+    pub fn record_sale(&mut self, customer: usize, price: f64, wtp: f64) {
+        self.resolutions[customer] = Some(Resolution::Bought { price, wtp });
+    }
+
+    pub fn record_quit(&mut self, customer: usize, wtp: f64) {
+        self.resolutions[customer] = Some(Resolution::Quit { wtp });
+    }
+
+    /// Customers without a resolution are still in the market at the end of the
+    /// horizon. The deadweight loss adds the wtp without the seasonal adjustment
+    /// of every customer who did not buy, taken when they quit or at the end of
+    /// the horizon, so it does not depend on where the horizon ends in the season.
+    pub fn finish(&self, customers: &[Customer], n_groups: usize) -> WelfareMetrics {
+        let mut metrics = WelfareMetrics::default();
+        let mut prices = Vec::new();
+        let mut group_prices = vec![Vec::new(); n_groups];
+
+        for (customer, resolution) in customers.iter().zip(self.resolutions.iter()) {
+            match resolution {
+                Some(Resolution::Bought { price, wtp }) => {
+                    metrics.consumer_surplus += wtp - price;
+                    metrics.producer_surplus += price;
+                    prices.push(*price);
+                    group_prices[customer.group() as usize].push(*price);
+                }
+                Some(Resolution::Quit { wtp }) => metrics.deadweight_loss += wtp,
+                None => metrics.deadweight_loss += customer.wtp(),
+            }
+        }
+
+        metrics.total_welfare = metrics.consumer_surplus + metrics.producer_surplus;
+        metrics.price_dispersion = if prices.is_empty() { 0.0 } else { std_dev(&prices) };
+        metrics.inequality = PriceInequality::from_prices(&prices);
+        metrics.inequality_by_group = group_prices
+            .iter()
+            .map(|prices| PriceInequality::from_prices(prices))
+            .collect();
+        metrics
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn std_dev(values: &[f64]) -> f64 {
+    let mean = mean(values);
+    (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64).sqrt()
+}
+
+/// Gini coefficient of non-negative values, 0 means everybody paid the same.
+pub fn gini(values: &[f64]) -> f64 {
+    let total: f64 = values.iter().sum();
+    if values.is_empty() || total <= 0.0 {
+        return 0.0;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let n = sorted.len() as f64;
+    let weighted: f64 = sorted
+        .iter()
+        .enumerate()
+        .map(|(i, value)| (i as f64 + 1.0) * value)
+        .sum();
+    2.0 * weighted / (n * total) - (n + 1.0) / n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_prices_have_no_inequality() {
+        let inequality = PriceInequality::from_prices(&[250.0; 4]);
+        assert_eq!(inequality.n_buyers, 4);
+        assert_eq!(inequality.mean_price, 250.0);
+        assert!(inequality.gini.abs() < 1e-12);
+        assert!(inequality.coefficient_of_variation.abs() < 1e-12);
+    }
+
+    #[test]
+    fn one_buyer_paying_everything_has_gini_of_one_minus_one_over_n() {
+        assert!((gini(&[0.0, 0.0, 0.0, 100.0]) - 0.75).abs() < 1e-12);
+        assert!((gini(&[100.0, 0.0, 0.0, 0.0]) - 0.75).abs() < 1e-12);
+        assert_eq!(gini(&[]), 0.0);
+        assert_eq!(gini(&[0.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn coefficient_of_variation_uses_the_population_deviation() {
+        let inequality = PriceInequality::from_prices(&[100.0, 300.0]);
+        assert_eq!(inequality.mean_price, 200.0);
+        assert!((inequality.coefficient_of_variation - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn every_non_buyer_counts_towards_the_deadweight_loss() {
+        let customers: Vec<Customer> = [(0, 300.0), (0, 200.0), (1, 500.0)]
+            .iter()
+            .enumerate()
+            .map(|(id, &(group, wtp))| Customer::new(id as i32, group, group, wtp, 2.0 * wtp, vec![]))
+            .collect();
+
+        let mut tracker = WelfareTracker::new(customers.len());
+        tracker.record_sale(0, 250.0, 320.0);
+        tracker.record_quit(1, 200.0);
+        let metrics = tracker.finish(&customers, 2);
+
+        assert_eq!(metrics.consumer_surplus, 70.0);
+        assert_eq!(metrics.producer_surplus, 250.0);
+        assert_eq!(metrics.total_welfare, 320.0);
+        // the quitter and the customer still in the market
+        assert_eq!(metrics.deadweight_loss, 700.0);
+        assert_eq!(metrics.price_dispersion, 0.0);
+        assert_eq!(metrics.inequality_by_group[0].n_buyers, 1);
+        assert_eq!(metrics.inequality_by_group[1].n_buyers, 0);
+    }
+}